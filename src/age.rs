@@ -0,0 +1,132 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+
+/// Reference time of age filters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Clock {
+    System,
+    /// Time the data set was generated at
+    Fixed(i64),
+}
+
+impl Clock {
+    pub fn now(&self) -> i64 {
+        match *self {
+            Clock::System => SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0),
+            Clock::Fixed(timestamp) => timestamp,
+        }
+    }
+}
+
+/// First moment after the birthday anniversary the given number of years earlier,
+/// Feb 29 birthdays come on Mar 1 in common years
+fn years_before(timestamp: i64, years: i32) -> Option<i64> {
+    let dt = NaiveDateTime::from_timestamp_opt(timestamp, 0)?;
+    let year = dt.year().checked_sub(years)?;
+    match NaiveDate::from_ymd_opt(year, dt.month(), dt.day()) {
+        Some(date) => Some(date.and_time(dt.time()).timestamp() + 1),
+        // everyone born on Feb 28 has already had the birthday on Feb 29
+        None => Some(NaiveDate::from_ymd_opt(year, 3, 1)?.and_hms(0, 0, 0).timestamp()),
+    }
+}
+
+/// Exclusive upper bound of birth dates of those who are at least `age` years old at `now`
+pub fn born_by(age: i64, now: i64) -> Option<i64> {
+    if age < 0 || age > i32::MAX as i64 {
+        return None;
+    }
+    years_before(now, age as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
+
+    use super::*;
+
+    fn is_leap(year: i32) -> bool {
+        (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+    }
+
+    /// Full years passed since the birth date, written independently of `born_by`:
+    /// the birthday is compared as (month, day, seconds of day) and a Feb 29 birthday
+    /// is celebrated at the start of Mar 1 in common years
+    fn age_at(birth_date: i64, now: i64) -> i32 {
+        let birth = NaiveDateTime::from_timestamp_opt(birth_date, 0).unwrap();
+        let today = NaiveDateTime::from_timestamp_opt(now, 0).unwrap();
+        let birthday = if birth.month() == 2 && birth.day() == 29 && !is_leap(today.year()) {
+            (3, 1, 0)
+        } else {
+            (birth.month(), birth.day(), birth.num_seconds_from_midnight())
+        };
+        let years = today.year() - birth.year();
+        if (today.month(), today.day(), today.num_seconds_from_midnight()) < birthday {
+            years - 1
+        } else {
+            years
+        }
+    }
+
+    fn ts(year: i32, month: u32, day: u32, hour: u32) -> i64 {
+        NaiveDate::from_ymd(year, month, day).and_hms(hour, 0, 0).timestamp()
+    }
+
+    #[test]
+    fn age_at_boundaries() {
+        let cases = [
+            // birth date, now, age
+            (ts(1990, 6, 15, 12), ts(2020, 6, 15, 12), 30),
+            (ts(1990, 6, 15, 12), ts(2020, 6, 15, 11), 29),
+            (ts(1990, 6, 15, 12), ts(2020, 6, 14, 23), 29),
+            (ts(1990, 6, 15, 12), ts(1990, 6, 15, 12), 0),
+            (ts(1990, 12, 31, 0), ts(1991, 1, 1, 0), 0),
+            // leap day birthdays
+            (ts(2000, 2, 29, 0), ts(2001, 2, 28, 23), 0),
+            (ts(2000, 2, 29, 0), ts(2001, 3, 1, 0), 1),
+            (ts(2000, 2, 29, 10), ts(2001, 3, 1, 5), 1),
+            (ts(2000, 2, 29, 0), ts(2004, 2, 29, 0), 4),
+            (ts(2000, 2, 28, 0), ts(2001, 2, 28, 0), 1),
+            (ts(2000, 3, 1, 0), ts(2004, 2, 29, 0), 3),
+            (ts(2023, 2, 28, 11), ts(2024, 2, 29, 10), 1),
+            // before 1970
+            (ts(1950, 6, 15, 0), ts(2020, 6, 15, 0), 70),
+            (ts(1950, 6, 15, 0), ts(2020, 6, 14, 0), 69),
+            (ts(1920, 2, 29, 0), ts(1969, 3, 1, 0), 49),
+            (ts(1920, 2, 29, 0), ts(1969, 2, 28, 0), 48),
+        ];
+        for &(birth_date, now, age) in cases.iter() {
+            assert_eq!(age_at(birth_date, now), age, "born {} at {}", birth_date, now);
+        }
+    }
+
+    #[test]
+    fn born_by_boundaries() {
+        let cases = [
+            // age, now, exclusive bound of birth dates
+            (30, ts(2020, 6, 15, 12), Some(ts(1990, 6, 15, 12) + 1)),
+            (0, ts(2020, 6, 15, 12), Some(ts(2020, 6, 15, 12) + 1)),
+            (1, ts(2024, 2, 29, 10), Some(ts(2023, 3, 1, 0))),
+            (4, ts(2024, 2, 29, 10), Some(ts(2020, 2, 29, 10) + 1)),
+            (1, ts(2021, 3, 1, 5), Some(ts(2020, 3, 1, 5) + 1)),
+            (100, ts(2017, 1, 1, 0), Some(ts(1917, 1, 1, 0) + 1)),
+            (50, ts(2000, 2, 29, 0), Some(ts(1950, 3, 1, 0))),
+            (-1, ts(2017, 1, 1, 0), None),
+        ];
+        for &(age, now, expected) in cases.iter() {
+            assert_eq!(born_by(age, now), expected, "age {} at {}", age, now);
+        }
+    }
+
+    #[test]
+    fn born_by_agrees_with_age_at() {
+        let moments = [ts(2024, 2, 29, 10), ts(2023, 2, 28, 23), ts(2023, 3, 1, 0), ts(1969, 12, 31, 12)];
+        for &now in moments.iter() {
+            for &age in [0, 1, 3, 4, 30, 80].iter() {
+                let bound = born_by(age, now).unwrap();
+                assert_eq!(age_at(bound - 1, now), age as i32, "age {} at {}", age, now);
+                assert!(age_at(bound, now) < age as i32, "age {} at {}", age, now);
+            }
+        }
+    }
+}
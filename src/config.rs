@@ -0,0 +1,99 @@
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use age::Clock;
+use decimal::{self, Precision, Rounding};
+
+/// What to do with loaded visits referencing missing user or location
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OrphanPolicy {
+    /// Store the visit, it's not reachable by user visits and location marks
+    Keep,
+    /// Drop the visit
+    Skip,
+    /// Stop the server
+    Fail,
+}
+
+/// Deployment settings read from the environment
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Benchmark compatible errors: 400/404 with an empty body
+    pub strict_errors: bool,
+    /// Reject unknown fields, `id` and empty partial updates in POST bodies
+    pub strict_body: bool,
+    /// Reject unknown and repeated single valued query parameters
+    pub strict_query: bool,
+    /// Maximum accepted POST body size in bytes
+    pub max_body_size: usize,
+    /// Serve `/admin/memory`
+    pub admin_memory: bool,
+    pub orphan_visits: OrphanPolicy,
+    /// Reference time of ages: `REFERENCE_TIME`, the first line of `OPTIONS_PATH`
+    /// (`options.txt` in the data directory by default) or the system time
+    pub clock: Clock,
+    /// Fractional digits of averages (`AVG_PRECISION`, default 5, at most 15)
+    /// and rounding of ties (`AVG_ROUNDING` half-even by default or half-up)
+    pub avg_precision: Precision,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        Config {
+            strict_errors: env_flag("STRICT_ERRORS"),
+            strict_body: env_flag("STRICT_BODY"),
+            strict_query: env_flag("STRICT_QUERY"),
+            max_body_size: env::var("MAX_BODY_SIZE").ok().and_then(|v| v.parse().ok()).unwrap_or(64 * 1024),
+            admin_memory: env_flag("ADMIN_MEMORY"),
+            orphan_visits: match env::var("ORPHAN_VISITS").as_ref().map(|v| v.as_str()) {
+                Ok("skip") => OrphanPolicy::Skip,
+                Ok("fail") => OrphanPolicy::Fail,
+                _ => OrphanPolicy::Keep,
+            },
+            clock: reference_time().map_or(Clock::System, Clock::Fixed),
+            avg_precision: Precision {
+                digits: env::var("AVG_PRECISION").ok().and_then(|v| v.parse().ok()).unwrap_or(5).min(decimal::MAX_DIGITS),
+                rounding: match env::var("AVG_ROUNDING").as_ref().map(|v| v.as_str()) {
+                    Ok("half-up") => Rounding::HalfUp,
+                    _ => Rounding::HalfEven,
+                },
+            },
+        }
+    }
+}
+
+/// `REFERENCE_TIME` must be a unix timestamp, an unreadable options file is only reported
+fn reference_time() -> Option<i64> {
+    if let Ok(value) = env::var("REFERENCE_TIME") {
+        match value.trim().parse() {
+            Ok(timestamp) => return Some(timestamp),
+            Err(_) => panic!("REFERENCE_TIME must be a unix timestamp, got {:?}", value),
+        }
+    }
+
+    let path = match env::var("OPTIONS_PATH") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => Path::new(&env::var("DATA_PATH").unwrap_or("/root".to_string())).join("options.txt"),
+    };
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+    let line = BufReader::new(file).lines().next().and_then(|l| l.ok()).unwrap_or_default();
+    match line.trim().parse() {
+        Ok(timestamp) => Some(timestamp),
+        Err(_) => {
+            println!("{}: expected a unix timestamp on the first line, using the system time", path.display());
+            None
+        },
+    }
+}
+
+fn env_flag(name: &str) -> bool {
+    match env::var(name) {
+        Ok(value) => value == "1" || value == "true",
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,151 @@
+use std::io;
+
+use serde::Serialize;
+use serde_json::ser::{Formatter, Serializer};
+
+/// Rounding of the last kept digit when the rest is exactly half
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rounding {
+    /// Away from zero
+    HalfUp,
+    /// To the even digit
+    HalfEven,
+}
+
+/// Decimal digits kept in averages and their rounding
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Precision {
+    pub digits: u32,
+    pub rounding: Rounding,
+}
+
+/// Most digits kept, f64 tells apart all fractions of 15 digits below 8 so averages
+/// of marks keep them when serialized by `to_json`
+pub const MAX_DIGITS: u32 = 15;
+
+/// Exact `sum / count` rounded to the precision digits, trailing zeros are trimmed
+/// but one fractional digit is kept, zero count gives `0.0` like `round_ratio` serialized
+pub fn format_ratio(sum: i64, count: i64, precision: &Precision) -> String {
+    if count == 0 {
+        return "0.0".to_string();
+    }
+    let negative = (sum < 0) != (count < 0);
+    let (sum, count) = (sum.unsigned_abs() as u128, count.unsigned_abs() as u128);
+    let scale = 10u128.pow(precision.digits);
+
+    let scaled = sum * scale;
+    let mut units = scaled / count;
+    let twice_rest = 2 * (scaled % count);
+    let round_up = match precision.rounding {
+        Rounding::HalfUp => twice_rest >= count,
+        Rounding::HalfEven => twice_rest > count || (twice_rest == count && units % 2 == 1),
+    };
+    if round_up {
+        units += 1;
+    }
+
+    let sign = if negative && units > 0 { "-" } else { "" };
+    if precision.digits == 0 {
+        return format!("{}{}", sign, units);
+    }
+    let fraction = format!("{:0width$}", units % scale, width = precision.digits as usize);
+    let fraction = fraction.trim_end_matches('0');
+    format!("{}{}.{}", sign, units / scale, if fraction.is_empty() { "0" } else { fraction })
+}
+
+/// Rounded `sum / count` as a number for `to_json`, its shortest form is the formatted one
+pub fn round_ratio(sum: i64, count: i64, precision: &Precision) -> f64 {
+    format_ratio(sum, count, precision).parse().unwrap_or(0.0)
+}
+
+/// Value without an exact ratio, like a deviation, rounded to the precision digits
+pub fn round_float(value: f64, precision: &Precision) -> f64 {
+    let scale = 10f64.powi(precision.digits as i32);
+    let units = match precision.rounding {
+        Rounding::HalfUp => (value * scale).round(),
+        Rounding::HalfEven => (value * scale).round_ties_even(),
+    };
+    units / scale
+}
+
+/// Writes floats in their shortest form, the default one could add digits to rounded values
+struct ShortestFloats;
+
+impl Formatter for ShortestFloats {
+    fn write_f64<W: ?Sized + io::Write>(&mut self, writer: &mut W, value: f64) -> io::Result<()> {
+        let formatted = value.to_string();
+        writer.write_all(formatted.as_bytes())?;
+        if formatted.contains('.') {
+            Ok(())
+        } else {
+            writer.write_all(b".0")
+        }
+    }
+}
+
+/// JSON of a response carrying rounded averages
+pub fn to_json<T: Serialize>(value: &T) -> String {
+    let mut serializer = Serializer::with_formatter(Vec::new(), ShortestFloats);
+    if value.serialize(&mut serializer).is_err() {
+        return String::new();
+    }
+    String::from_utf8(serializer.into_inner()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_ratio_rounding() {
+        let cases = [
+            // sum, count, digits, rounding, formatted
+            (7, 4, 5, Rounding::HalfEven, "1.75"),
+            (8, 2, 5, Rounding::HalfEven, "4.0"),
+            (5, 3, 5, Rounding::HalfEven, "1.66667"),
+            (1, 3, 5, Rounding::HalfUp, "0.33333"),
+            (1, 64, 5, Rounding::HalfEven, "0.01562"),
+            (1, 64, 5, Rounding::HalfUp, "0.01563"),
+            (5, 2, 0, Rounding::HalfEven, "2"),
+            (7, 2, 0, Rounding::HalfEven, "4"),
+            (5, 2, 0, Rounding::HalfUp, "3"),
+            (-5, 2, 0, Rounding::HalfUp, "-3"),
+            (-1, 64, 5, Rounding::HalfEven, "-0.01562"),
+            (0, 3, 5, Rounding::HalfUp, "0.0"),
+            (3, 0, 5, Rounding::HalfUp, "0.0"),
+            (0, 0, 0, Rounding::HalfEven, "0.0"),
+            (399999, 100000, 4, Rounding::HalfUp, "4.0"),
+        ];
+        for &(sum, count, digits, rounding, expected) in cases.iter() {
+            let precision = Precision { digits, rounding };
+            assert_eq!(format_ratio(sum, count, &precision), expected, "{} / {}", sum, count);
+        }
+    }
+
+    #[test]
+    fn to_json_keeps_formatted_digits() {
+        let precision = Precision { digits: MAX_DIGITS, rounding: Rounding::HalfEven };
+        for count in 0..200 {
+            for sum in 0..5 * count + 1 {
+                let formatted = format_ratio(sum, count, &precision);
+                let serialized = to_json(&round_ratio(sum, count, &precision));
+                assert_eq!(serialized, formatted, "{} / {}", sum, count);
+            }
+        }
+    }
+
+    #[test]
+    fn round_float_digits() {
+        let cases = [
+            // value, digits, rounding, rounded
+            (3.0f64.sqrt(), 5, Rounding::HalfEven, 1.73205),
+            (1.25, 1, Rounding::HalfEven, 1.2),
+            (1.25, 1, Rounding::HalfUp, 1.3),
+            (0.5, 0, Rounding::HalfEven, 0.0),
+            (1.0 / 3.0, 15, Rounding::HalfUp, 0.333333333333333),
+        ];
+        for &(value, digits, rounding, expected) in cases.iter() {
+            assert_eq!(round_float(value, &Precision { digits, rounding }), expected, "{}", value);
+        }
+    }
+}
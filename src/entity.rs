@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use error::ApiError;
+use validate::Validate;
+
+/// Value stored in a repository under an integer key
+pub trait Keyed: Clone {
+    fn key(&self) -> i32;
+}
+
+/// Entity exposed by the API, created from a complete json body
+/// and updated from a partial one
+pub trait Entity: Keyed + Validate + Serialize + DeserializeOwned {
+    type Partial: PartialEntity;
+
+    /// Names of all json fields, the key field is `id`
+    const FIELDS: &'static [&'static str];
+
+    /// String fields restricted to a set of values
+    const CHOICES: &'static [(&'static str, &'static [&'static str])] = &[];
+
+    /// Apply fields present in the partial update
+    fn merge(&mut self, partial: Self::Partial);
+}
+
+/// Partial update body of an entity, all fields are optional
+pub trait PartialEntity: DeserializeOwned {
+    /// Name of the field explicitly set to null
+    fn null_field(&self) -> Option<&'static str>;
+}
+
+/// Field of a partial update: absent from the body, explicit null or a value
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Field<T> {
+    // absent fields are filled by #[serde(default)]
+    #[default]
+    Absent,
+    Null,
+    Value(T),
+}
+
+impl<T> Field<T> {
+    pub fn is_null(&self) -> bool {
+        matches!(*self, Field::Null)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Field<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Field<T>, D::Error>
+        where D: Deserializer<'de>
+    {
+        Ok(match Option::<T>::deserialize(deserializer)? {
+            Some(value) => Field::Value(value),
+            None => Field::Null,
+        })
+    }
+}
+
+/// Name of the first field which is null
+pub fn find_null(fields: &[(&'static str, bool)]) -> Option<&'static str> {
+    fields.iter().find(|&&(_, null)| null).map(|&(name, _)| name)
+}
+
+/// Strict mode check of the body keys before it is deserialized:
+/// only known fields are allowed and partial updates must change something except `id`
+fn check_fields<T: Entity>(body: &str, partial: bool) -> Result<(), ApiError> {
+    let value = serde_json::from_str::<serde_json::Value>(body).map_err(ApiError::malformed)?;
+    let object = value.as_object().ok_or_else(|| ApiError::malformed("expected json object"))?;
+    for key in object.keys() {
+        if partial && key == "id" {
+            return Err(ApiError::unprocessable("id", "can't be updated"));
+        }
+        if !T::FIELDS.contains(&key.as_str()) {
+            return Err(ApiError::malformed_field(key, "unknown field"));
+        }
+    }
+    if partial && object.is_empty() {
+        return Err(ApiError::malformed("nothing to update"));
+    }
+    Ok(())
+}
+
+/// Field outside of its choices which made the body fail to deserialize
+fn choice_error<T: Entity>(body: &str) -> Option<ApiError> {
+    let value = serde_json::from_str::<serde_json::Value>(body).ok()?;
+    let object = value.as_object()?;
+    T::CHOICES.iter().find_map(|&(field, choices)| match object.get(field) {
+        Some(&serde_json::Value::Null) | None => None,
+        Some(value) if value.as_str().is_some_and(|v| choices.contains(&v)) => None,
+        Some(_) => Some(ApiError::unprocessable(field, &format!("must be one of {}", choices.join(", ")))),
+    })
+}
+
+/// Deserialization error of the body, reported on the field when it is out of its choices
+fn body_error<T: Entity>(body: &str, err: serde_json::Error) -> ApiError {
+    choice_error::<T>(body).unwrap_or_else(|| ApiError::malformed(err))
+}
+
+pub fn parse_entity<T: Entity>(body: &str, strict: bool) -> Result<T, ApiError> {
+    if strict {
+        check_fields::<T>(body, false)?;
+    }
+    let item = serde_json::from_str::<T>(body).map_err(|err| body_error::<T>(body, err))?;
+    item.validate()?;
+    Ok(item)
+}
+
+pub fn parse_partial<T: Entity>(body: &str, strict: bool) -> Result<T::Partial, ApiError> {
+    if strict {
+        check_fields::<T>(body, true)?;
+    }
+    let partial = serde_json::from_str::<T::Partial>(body).map_err(|err| body_error::<T>(body, err))?;
+    match partial.null_field() {
+        Some(field) => Err(ApiError::unprocessable(field, "must not be null")),
+        None => Ok(partial),
+    }
+}
+
+/// Entity state before and after the partial update, not saved yet
+pub struct Merged<T> {
+    pub before: T,
+    pub after: T,
+}
+
+#[derive(Clone)]
+pub struct Repository<T> {
+    items: Arc<RwLock<BTreeMap<i32, T>>>,
+}
+
+impl<T: Keyed> Repository<T> {
+    pub fn new() -> Repository<T> {
+        Repository { items: Arc::new(RwLock::new(BTreeMap::new())) }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, BTreeMap<i32, T>> {
+        self.items.read().unwrap()
+    }
+
+    pub fn exists(&self, id: i32) -> bool {
+        self.items.read().ok().map(|guard| guard.contains_key(&id)).unwrap_or(false)
+    }
+
+    pub fn load(&self, id: i32) -> Option<T> {
+        self.items.read().ok().and_then(|guard| guard.get(&id).cloned())
+    }
+
+    pub fn save(&self, item: T) {
+        if let Ok(mut guard) = self.items.write() {
+            guard.insert(item.key(), item);
+        }
+    }
+
+    /// Change the item in place under the write lock, None if it is missing
+    pub fn update<R, F: FnOnce(&mut T) -> R>(&self, id: i32, change: F) -> Option<R> {
+        self.items.write().ok().and_then(|mut guard| guard.get_mut(&id).map(change))
+    }
+
+    /// Save item only if the key is not taken yet
+    pub fn insert_unique(&self, item: T) -> bool {
+        if let Ok(mut guard) = self.items.write() {
+            if let Entry::Vacant(entry) = guard.entry(item.key()) {
+                entry.insert(item);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl<T: Entity> Repository<T> {
+    pub fn content(&self, id: i32) -> Option<String> {
+        self.items.read().ok().and_then(|guard| guard.get(&id).map(|val| serde_json::to_string(val).unwrap_or_default()))
+    }
+
+    pub fn merge(&self, id: i32, partial: T::Partial) -> Result<Merged<T>, ApiError> {
+        let before = self.load(id).ok_or(ApiError::NotFound)?;
+        let mut after = before.clone();
+        after.merge(partial);
+        after.validate()?;
+        Ok(Merged { before, after })
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+
+use serde_json;
+use tokio_minihttp::Response;
+
+/// Failure of a request to the Travels service
+#[derive(Debug)]
+pub enum ApiError {
+    /// Body or query parameter can't be decoded
+    Malformed { message: String, field: Option<String> },
+    /// Requested entity does not exist
+    NotFound,
+    /// Entity with the same unique value already exists
+    Conflict { message: String, field: String },
+    /// Request body exceeds the configured limit
+    PayloadTooLarge { limit: usize },
+    /// Body is well formed but some field has an unacceptable value
+    Unprocessable { message: String, field: String },
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'a str>,
+}
+
+impl ApiError {
+    pub fn malformed<T: fmt::Display>(err: T) -> ApiError {
+        ApiError::Malformed { message: err.to_string(), field: None }
+    }
+
+    pub fn malformed_field<T: fmt::Display>(field: &str, err: T) -> ApiError {
+        ApiError::Malformed { message: err.to_string(), field: Some(field.to_string()) }
+    }
+
+    pub fn conflict(field: &str, message: &str) -> ApiError {
+        ApiError::Conflict { message: message.to_string(), field: field.to_string() }
+    }
+
+    pub fn unprocessable(field: &str, message: &str) -> ApiError {
+        ApiError::Unprocessable { message: message.to_string(), field: field.to_string() }
+    }
+
+    pub fn field(&self) -> Option<&str> {
+        match *self {
+            ApiError::Malformed { ref field, .. } => field.as_ref().map(|f| f.as_str()),
+            ApiError::Conflict { ref field, .. } |
+            ApiError::Unprocessable { ref field, .. } => Some(field),
+            _ => None,
+        }
+    }
+
+    pub fn status(&self) -> (u32, &'static str) {
+        match *self {
+            ApiError::Malformed { .. } => (400, "Bad Request"),
+            ApiError::NotFound => (404, "Not Found"),
+            ApiError::Conflict { .. } => (409, "Conflict"),
+            ApiError::PayloadTooLarge { .. } => (413, "Payload Too Large"),
+            ApiError::Unprocessable { .. } => (422, "Unprocessable Entity"),
+        }
+    }
+
+    /// Write error to the response, strict mode keeps the benchmark contract:
+    /// only 400 and 404 status codes with an empty body
+    pub fn render(&self, resp: &mut Response, strict: bool) {
+        if strict {
+            match *self {
+                ApiError::NotFound => resp.status_code(404, "Not Found"),
+                _ => resp.status_code(400, "Bad Request"),
+            };
+            return;
+        }
+
+        let (code, message) = self.status();
+        resp.status_code(code, message);
+
+        let body = ErrorBody { error: self.to_string(), field: self.field() };
+        if let Ok(content) = serde_json::to_string(&body) {
+            resp.body(content.len(), &content);
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ApiError::Malformed { ref message, .. } |
+            ApiError::Conflict { ref message, .. } |
+            ApiError::Unprocessable { ref message, .. } => f.write_str(message),
+            ApiError::NotFound => f.write_str("not found"),
+            ApiError::PayloadTooLarge { limit } => write!(f, "body exceeds {} bytes", limit),
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use error::ApiError;
+use query;
+
+/// Comparison of a record field with the filter values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eq,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    In,
+    Prefix,
+}
+
+impl Op {
+    fn parse(name: &str) -> Option<Op> {
+        match name {
+            "eq" => Some(Op::Eq),
+            "lt" => Some(Op::Lt),
+            "lte" => Some(Op::Lte),
+            "gt" => Some(Op::Gt),
+            "gte" => Some(Op::Gte),
+            "in" => Some(Op::In),
+            "prefix" => Some(Op::Prefix),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Int,
+    Str,
+    /// String restricted to the listed values
+    OneOf(&'static [&'static str]),
+}
+
+/// Filterable field of an endpoint
+pub struct FieldSpec {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub ops: &'static [Op],
+}
+
+/// Shorthand parameter like `fromDate` for a field comparison, `map` converts the integer
+/// parameter to the field value at the reference time, otherwise it's of the field type
+pub struct Alias {
+    pub param: &'static str,
+    pub field: &'static str,
+    pub op: Op,
+    pub map: Option<fn(i64, i64) -> Option<i64>>,
+}
+
+/// Filters accepted by an endpoint: `field=v`, `field.<op>=v`, `field.not.<op>=v`,
+/// `field.not=v` and the aliases, `in` takes a comma separated list
+pub struct Schema {
+    pub fields: &'static [FieldSpec],
+    pub aliases: &'static [Alias],
+}
+
+impl Schema {
+    /// Parameter is an alias or addresses a field of the schema
+    pub fn accepts(&self, key: &str) -> bool {
+        let name = key.split('.').next().unwrap_or("");
+        self.aliases.iter().any(|a| a.param == key) || self.fields.iter().any(|f| f.name == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Str(String),
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::Str(ref value) => Some(value),
+            Value::Int(_) => None,
+        }
+    }
+}
+
+/// Field value of a filtered record
+pub enum FieldValue<'a> {
+    Int(i64),
+    Str(&'a str),
+}
+
+/// Record which could be matched by filters of its schema
+pub trait Filterable {
+    fn field(&self, name: &str) -> Option<FieldValue<'_>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub field: &'static str,
+    pub op: Op,
+    pub negate: bool,
+    pub values: Vec<Value>,
+}
+
+impl Filter {
+    pub fn matches<T: Filterable>(&self, record: &T) -> bool {
+        let value = match record.field(self.field) {
+            Some(value) => value,
+            None => return false,
+        };
+        let matched = self.values.iter().any(|expected| compare(&value, self.op, expected));
+        matched != self.negate
+    }
+}
+
+fn compare(value: &FieldValue, op: Op, expected: &Value) -> bool {
+    match (value, expected) {
+        (&FieldValue::Int(value), &Value::Int(expected)) => match op {
+            Op::Eq | Op::In => value == expected,
+            Op::Lt => value < expected,
+            Op::Lte => value <= expected,
+            Op::Gt => value > expected,
+            Op::Gte => value >= expected,
+            Op::Prefix => false,
+        },
+        (&FieldValue::Str(value), Value::Str(expected)) => match op {
+            Op::Eq | Op::In => value == expected,
+            Op::Lt => value < expected.as_str(),
+            Op::Lte => value <= expected.as_str(),
+            Op::Gt => value > expected.as_str(),
+            Op::Gte => value >= expected.as_str(),
+            Op::Prefix => value.starts_with(expected.as_str()),
+        },
+        _ => false,
+    }
+}
+
+/// Record matches all the filters
+pub fn matches_all<T: Filterable>(filters: &[Filter], record: &T) -> bool {
+    filters.iter().all(|f| f.matches(record))
+}
+
+/// Values of the positive `eq` or `in` filter on the field, they could pick candidates from an index
+pub fn equal_values<'a>(filters: &'a [Filter], field: &str) -> Option<&'a [Value]> {
+    filters.iter()
+        .find(|f| f.field == field && !f.negate && (f.op == Op::Eq || f.op == Op::In))
+        .map(|f| &f.values[..])
+}
+
+/// Exclusive bounds of an integer field set by its positive comparison filters
+pub fn int_range(filters: &[Filter], field: &str) -> (Option<i64>, Option<i64>) {
+    let (mut after, mut before): (Option<i64>, Option<i64>) = (None, None);
+    for f in filters.iter().filter(|f| f.field == field && !f.negate) {
+        for value in &f.values {
+            if let Value::Int(value) = *value {
+                match f.op {
+                    Op::Gt => after = Some(after.map_or(value, |a| a.max(value))),
+                    Op::Gte => after = Some(after.map_or(value.saturating_sub(1), |a| a.max(value.saturating_sub(1)))),
+                    Op::Lt => before = Some(before.map_or(value, |b| b.min(value))),
+                    Op::Lte => before = Some(before.map_or(value.saturating_add(1), |b| b.min(value.saturating_add(1)))),
+                    _ => (),
+                }
+            }
+        }
+    }
+    (after, before)
+}
+
+/// Parse filters of the schema, parameters of other kinds are left to their parsers,
+/// repeated `field=v` parameters are merged into a single `in` filter
+pub fn parse(schema: &Schema, query: &str, now: i64) -> Result<Vec<Filter>, ApiError> {
+    let mut filters: Vec<Filter> = Vec::new();
+    for (key, raw) in query::pairs(query) {
+        let filter = match parse_param(schema, key, raw, now)? {
+            Some(filter) => filter,
+            None => continue,
+        };
+        if filter.op == Op::Eq && !filter.negate {
+            let same = filters.iter_mut().find(|f| f.field == filter.field && !f.negate && (f.op == Op::Eq || f.op == Op::In));
+            if let Some(same) = same {
+                same.op = Op::In;
+                same.values.extend(filter.values);
+                continue;
+            }
+        }
+        filters.push(filter);
+    }
+    Ok(filters)
+}
+
+fn parse_param(schema: &Schema, key: &str, raw: &str, now: i64) -> Result<Option<Filter>, ApiError> {
+    if let Some(alias) = schema.aliases.iter().find(|a| a.param == key) {
+        let value = match (alias.map, schema.fields.iter().find(|f| f.name == alias.field)) {
+            (Some(map), _) => {
+                let value = query::parse_value::<i64>(key, raw)?;
+                Value::Int(map(value, now).ok_or_else(|| ApiError::malformed_field(key, "invalid value"))?)
+            },
+            (None, Some(spec)) => parse_typed(key, spec.kind, &query::decode_value(key, raw)?)?,
+            (None, None) => query::parse_value(key, raw).map(Value::Int)?,
+        };
+        return Ok(Some(Filter { field: alias.field, op: alias.op, negate: false, values: vec![value] }));
+    }
+
+    let mut parts = key.splitn(2, '.');
+    let name = parts.next().unwrap_or("");
+    let modifier = parts.next();
+    let spec = match schema.fields.iter().find(|f| f.name == name) {
+        Some(spec) => spec,
+        None if modifier.is_some() => return Err(ApiError::malformed_field(key, format!("unknown filter field {}", name))),
+        None => return Ok(None),
+    };
+
+    let (negate, op) = match modifier {
+        None => (false, Some(Op::Eq)),
+        Some("not") => (true, Some(Op::Eq)),
+        Some(modifier) if modifier.starts_with("not.") => (true, Op::parse(&modifier[4..])),
+        Some(modifier) => (false, Op::parse(modifier)),
+    };
+    let op = match op {
+        Some(op) if spec.ops.contains(&op) => op,
+        _ => return Err(ApiError::malformed_field(key, format!("unsupported operator for {}", name))),
+    };
+
+    let decoded = query::decode_value(key, raw)?;
+    let values = if op == Op::In {
+        decoded.split(',').map(|v| parse_typed(key, spec.kind, v)).collect::<Result<Vec<Value>, ApiError>>()?
+    } else {
+        vec![parse_typed(key, spec.kind, &decoded)?]
+    };
+    Ok(Some(Filter { field: spec.name, op, negate, values }))
+}
+
+fn parse_typed(key: &str, kind: Kind, value: &str) -> Result<Value, ApiError> {
+    match kind {
+        Kind::Int => query::parse_value(key, value).map(Value::Int),
+        Kind::Str => Ok(Value::Str(value.to_string())),
+        Kind::OneOf(allowed) => if allowed.contains(&value) {
+            Ok(Value::Str(value.to_string()))
+        } else {
+            Err(ApiError::malformed_field(key, format!("expected one of {}", allowed.join(", "))))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 100;
+
+    fn years_ago(years: i64, now: i64) -> Option<i64> {
+        if years < 0 { None } else { Some(now - years) }
+    }
+
+    const SCHEMA: Schema = Schema {
+        fields: &[
+            FieldSpec { name: "mark", kind: Kind::Int, ops: &[Op::Eq, Op::Lt, Op::Gte, Op::In] },
+            FieldSpec { name: "place", kind: Kind::Str, ops: &[Op::Eq, Op::In, Op::Prefix] },
+            FieldSpec { name: "gender", kind: Kind::OneOf(&["m", "f"]), ops: &[Op::Eq] },
+            FieldSpec { name: "born", kind: Kind::Int, ops: &[Op::Lt] },
+        ],
+        aliases: &[
+            Alias { param: "fromMark", field: "mark", op: Op::Gte, map: None },
+            Alias { param: "placePrefix", field: "place", op: Op::Prefix, map: None },
+            Alias { param: "olderThan", field: "born", op: Op::Lt, map: Some(years_ago) },
+        ],
+    };
+
+    fn int(value: i64) -> Value {
+        Value::Int(value)
+    }
+
+    fn string(value: &str) -> Value {
+        Value::Str(value.to_string())
+    }
+
+    #[test]
+    fn parse_accepted_forms() {
+        let cases = vec![
+            // query, (field, op, negate, values) of the parsed filters
+            ("mark=3", vec![("mark", Op::Eq, false, vec![int(3)])]),
+            ("mark.lt=3", vec![("mark", Op::Lt, false, vec![int(3)])]),
+            ("mark.not=3", vec![("mark", Op::Eq, true, vec![int(3)])]),
+            ("mark.not.lt=3", vec![("mark", Op::Lt, true, vec![int(3)])]),
+            ("mark.in=1,2", vec![("mark", Op::In, false, vec![int(1), int(2)])]),
+            ("mark.not.in=1,2", vec![("mark", Op::In, true, vec![int(1), int(2)])]),
+            ("place.prefix=New%20York", vec![("place", Op::Prefix, false, vec![string("New York")])]),
+            ("place.in=Rome,Paris", vec![("place", Op::In, false, vec![string("Rome"), string("Paris")])]),
+            ("gender=f", vec![("gender", Op::Eq, false, vec![string("f")])]),
+            ("fromMark=2", vec![("mark", Op::Gte, false, vec![int(2)])]),
+            ("placePrefix=Par", vec![("place", Op::Prefix, false, vec![string("Par")])]),
+            ("olderThan=30", vec![("born", Op::Lt, false, vec![int(70)])]),
+            ("limit=5&mark=1&fromDate=3", vec![("mark", Op::Eq, false, vec![int(1)])]),
+            ("", vec![]),
+            // repeated equality is merged into a single `in`
+            ("mark=1&mark=2", vec![("mark", Op::In, false, vec![int(1), int(2)])]),
+            ("mark.in=1,2&mark=3", vec![("mark", Op::In, false, vec![int(1), int(2), int(3)])]),
+            ("mark=1&mark.not=2", vec![("mark", Op::Eq, false, vec![int(1)]), ("mark", Op::Eq, true, vec![int(2)])]),
+            ("mark.not=1&mark=2", vec![("mark", Op::Eq, true, vec![int(1)]), ("mark", Op::Eq, false, vec![int(2)])]),
+            ("mark=1&place=Rome&mark=2", vec![
+                ("mark", Op::In, false, vec![int(1), int(2)]),
+                ("place", Op::Eq, false, vec![string("Rome")]),
+            ]),
+        ];
+        for (query, expected) in cases {
+            let filters = parse(&SCHEMA, query, NOW).unwrap_or_else(|err| panic!("{}: {:?}", query, err));
+            let parsed = filters.into_iter().map(|f| (f.field, f.op, f.negate, f.values)).collect::<Vec<_>>();
+            assert_eq!(parsed, expected, "{}", query);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            // query, field and message of the error
+            ("size.lt=1", "size.lt", "unknown filter field size"),
+            ("mark.prefix=1", "mark.prefix", "unsupported operator for mark"),
+            ("mark.not.between=1", "mark.not.between", "unsupported operator for mark"),
+            ("born=1", "born", "unsupported operator for born"),
+            ("mark=x", "mark", "invalid value"),
+            ("mark.in=1,x", "mark.in", "invalid value"),
+            ("gender=x", "gender", "expected one of m, f"),
+            ("place=%FF", "place", "invalid value"),
+            ("placePrefix=%FF", "placePrefix", "invalid value"),
+            ("fromMark=x", "fromMark", "invalid value"),
+            ("olderThan=x", "olderThan", "invalid value"),
+            ("olderThan=-1", "olderThan", "invalid value"),
+        ];
+        for &(query, field, message) in cases.iter() {
+            match parse(&SCHEMA, query, NOW) {
+                Err(ApiError::Malformed { message: ref m, field: Some(ref f) }) => {
+                    assert_eq!((f.as_str(), m.as_str()), (field, message), "{}", query)
+                },
+                other => panic!("{}: {:?}", query, other),
+            }
+        }
+    }
+}
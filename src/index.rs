@@ -0,0 +1,289 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::hash_map::Entry;
+use std::hash::Hash;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+/// Secondary index where every key belongs to a single entity
+#[derive(Clone)]
+pub struct UniqueIndex<K> {
+    items: Arc<RwLock<HashMap<K, i32>>>,
+}
+
+impl<K: Hash + Eq> UniqueIndex<K> {
+    pub fn new() -> UniqueIndex<K> {
+        UniqueIndex { items: Arc::new(RwLock::new(HashMap::new())) }
+    }
+
+    pub fn get(&self, key: &K) -> Option<i32> {
+        self.items.read().ok().and_then(|guard| guard.get(key).cloned())
+    }
+
+    /// Bind key to the entity, fails if it already belongs to another one
+    pub fn reserve(&self, key: K, id: i32) -> bool {
+        if let Ok(mut guard) = self.items.write() {
+            return match guard.entry(key) {
+                Entry::Occupied(entry) => *entry.get() == id,
+                Entry::Vacant(entry) => {
+                    entry.insert(id);
+                    true
+                },
+            };
+        }
+        false
+    }
+
+    /// Unbind key if it still belongs to the entity
+    pub fn release(&self, key: &K, id: i32) {
+        if let Ok(mut guard) = self.items.write() {
+            if guard.get(key) == Some(&id) {
+                guard.remove(key);
+            }
+        }
+    }
+}
+
+/// Secondary index where a key is shared by many entities
+#[derive(Clone)]
+pub struct MultiIndex<K> {
+    items: Arc<RwLock<HashMap<K, BTreeSet<i32>>>>,
+}
+
+impl<K: Hash + Eq> MultiIndex<K> {
+    pub fn new() -> MultiIndex<K> {
+        MultiIndex { items: Arc::new(RwLock::new(HashMap::new())) }
+    }
+
+    /// Ids of the entities having the key in ascending order
+    pub fn get<Q>(&self, key: &Q) -> Vec<i32>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        self.items.read().ok()
+            .and_then(|guard| guard.get(key).map(|ids| ids.iter().cloned().collect()))
+            .unwrap_or_default()
+    }
+
+    /// Ids of the entities having any of the keys in ascending order
+    pub fn get_any<'q, Q, I>(&self, keys: I) -> Vec<i32>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized + 'q, I: IntoIterator<Item = &'q Q>
+    {
+        match self.items.read() {
+            Ok(guard) => keys.into_iter()
+                .filter_map(|key| guard.get(key))
+                .flat_map(|ids| ids.iter().cloned())
+                .collect::<BTreeSet<i32>>()
+                .into_iter()
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub fn insert(&self, key: K, id: i32) {
+        if let Ok(mut guard) = self.items.write() {
+            guard.entry(key).or_default().insert(id);
+        }
+    }
+
+    pub fn remove(&self, key: &K, id: i32) {
+        if let Ok(mut guard) = self.items.write() {
+            let empty = match guard.get_mut(key) {
+                Some(ids) => {
+                    ids.remove(&id);
+                    ids.is_empty()
+                },
+                None => false,
+            };
+            if empty {
+                guard.remove(key);
+            }
+        }
+    }
+}
+
+/// Secondary index over an ordered value answering range queries
+#[derive(Clone)]
+pub struct OrderedIndex<K> {
+    items: Arc<RwLock<BTreeMap<K, BTreeSet<i32>>>>,
+}
+
+impl<K: Ord> OrderedIndex<K> {
+    pub fn new() -> OrderedIndex<K> {
+        OrderedIndex { items: Arc::new(RwLock::new(BTreeMap::new())) }
+    }
+
+    /// Ids of the entities with values strictly between the bounds in ascending order
+    pub fn between(&self, after: Option<K>, before: Option<K>) -> Vec<i32> {
+        let after = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let before = before.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut ids = match self.items.read() {
+            Ok(guard) => guard.range((after, before)).flat_map(|(_, ids)| ids.iter().cloned()).collect::<Vec<i32>>(),
+            Err(_) => Vec::new(),
+        };
+        ids.sort();
+        ids
+    }
+
+    pub fn insert(&self, key: K, id: i32) {
+        if let Ok(mut guard) = self.items.write() {
+            guard.entry(key).or_default().insert(id);
+        }
+    }
+
+    pub fn remove(&self, key: &K, id: i32) {
+        if let Ok(mut guard) = self.items.write() {
+            let empty = match guard.get_mut(key) {
+                Some(ids) => {
+                    ids.remove(&id);
+                    ids.is_empty()
+                },
+                None => false,
+            };
+            if empty {
+                guard.remove(key);
+            }
+        }
+    }
+}
+
+/// Running totals per key, entries back at the default value are dropped
+#[derive(Clone)]
+pub struct Aggregate<K, V> {
+    items: Arc<RwLock<HashMap<K, V>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Default + Clone + PartialEq> Aggregate<K, V> {
+    pub fn new() -> Aggregate<K, V> {
+        Aggregate { items: Arc::new(RwLock::new(HashMap::new())) }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        self.items.read().ok().and_then(|guard| guard.get(key).cloned())
+    }
+
+    pub fn entries(&self) -> Vec<(K, V)> {
+        self.items.read().ok()
+            .map(|guard| guard.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn update<F: FnOnce(&mut V)>(&self, key: K, f: F) {
+        if let Ok(mut guard) = self.items.write() {
+            let empty = {
+                let value = guard.entry(key.clone()).or_default();
+                f(value);
+                *value == V::default()
+            };
+            if empty {
+                guard.remove(&key);
+            }
+        }
+    }
+}
+
+/// Entity ranked by the average of its values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rank {
+    pub id: i32,
+    pub sum: i64,
+    pub count: i64,
+}
+
+impl Ord for Rank {
+    /// Higher exact average first, compared as a.sum / a.count > b.sum / b.count,
+    /// then more values and lower id
+    fn cmp(&self, other: &Rank) -> Ordering {
+        (other.sum as i128 * self.count as i128).cmp(&(self.sum as i128 * other.count as i128))
+            .then(other.count.cmp(&self.count))
+            .then(self.id.cmp(&other.id))
+    }
+}
+
+impl PartialOrd for Rank {
+    fn partial_cmp(&self, other: &Rank) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Default)]
+struct RankingItems {
+    order: BTreeSet<Rank>,
+    ranks: HashMap<i32, Rank>,
+}
+
+/// Entities ordered by the average of their values, those without values are left out
+#[derive(Clone)]
+pub struct Ranking {
+    items: Arc<RwLock<RankingItems>>,
+}
+
+impl Ranking {
+    pub fn new() -> Ranking {
+        Ranking { items: Arc::new(RwLock::new(RankingItems::default())) }
+    }
+
+    /// Move the entity to the place of its current sum and count
+    pub fn set(&self, id: i32, sum: i64, count: i64) {
+        if let Ok(mut guard) = self.items.write() {
+            if let Some(rank) = guard.ranks.remove(&id) {
+                guard.order.remove(&rank);
+            }
+            if count > 0 {
+                let rank = Rank { id, sum, count };
+                guard.order.insert(rank);
+                guard.ranks.insert(id, rank);
+            }
+        }
+    }
+
+    /// First `limit` ranks accepted by the predicate, from the highest average
+    pub fn top<F: FnMut(&Rank) -> bool>(&self, limit: usize, mut accept: F) -> Vec<Rank> {
+        match self.items.read() {
+            Ok(guard) => guard.order.iter().filter(|rank| accept(rank)).take(limit).cloned().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rank(id: i32, sum: i64, count: i64) -> Rank {
+        Rank { id, sum, count }
+    }
+
+    #[test]
+    fn rank_order() {
+        let cases = [
+            // higher, lower
+            (rank(2, 5, 2), rank(1, 7, 3)),
+            // 1/3 and 333333333333333/1000000000000000 are the same as f64
+            (rank(2, 1, 3), rank(1, 333333333333333, 1000000000000000)),
+            (rank(2, i64::MAX, 3), rank(1, i64::MAX - 1, 3)),
+            (rank(2, 9, 3), rank(1, 3, 1)),
+            (rank(1, 6, 2), rank(2, 6, 2)),
+        ];
+        for &(higher, lower) in cases.iter() {
+            assert_eq!(higher.cmp(&lower), Ordering::Less, "{:?} {:?}", higher, lower);
+            assert_eq!(lower.cmp(&higher), Ordering::Greater, "{:?} {:?}", lower, higher);
+        }
+        assert_eq!(rank(1, 4, 2).cmp(&rank(1, 4, 2)), Ordering::Equal);
+    }
+
+    #[test]
+    fn ranking_moves_changed_ranks() {
+        let ranking = Ranking::new();
+        ranking.set(1, 4, 2);
+        ranking.set(2, 5, 2);
+        ranking.set(3, 3, 1);
+        ranking.set(2, 5, 3);
+        ranking.set(3, 0, 0);
+        let ids = ranking.top(10, |_| true).iter().map(|rank| rank.id).collect::<Vec<i32>>();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(ranking.top(1, |rank| rank.count > 2), vec![rank(2, 5, 3)]);
+    }
+}
@@ -0,0 +1,116 @@
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::fmt;
+use std::mem::size_of;
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+lazy_static! {
+    static ref POOL: RwLock<HashSet<Arc<str>>> = RwLock::new(HashSet::new());
+}
+
+const MIN_SWEEP: usize = 1024;
+
+/// Pool size at which strings no longer used by any symbol are dropped,
+/// doubling the live size keeps the sweeps amortized and the pool bounded
+static SWEEP_AT: AtomicUsize = AtomicUsize::new(MIN_SWEEP);
+
+/// Interned immutable string, every distinct value is stored once
+/// and shared between entities and indexes.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(Arc<str>);
+
+impl Symbol {
+    pub fn new(value: &str) -> Symbol {
+        if let Some(found) = POOL.read().ok().and_then(|guard| guard.get(value).cloned()) {
+            return Symbol(found);
+        }
+
+        let mut guard = POOL.write().unwrap();
+        // may be inserted by another thread between the locks
+        if let Some(found) = guard.get(value) {
+            return Symbol(found.clone());
+        }
+        if guard.len() >= SWEEP_AT.load(Ordering::Relaxed) {
+            // a string held only by the pool can't be handed out again without the write lock
+            guard.retain(|item| Arc::strong_count(item) > 1);
+            SWEEP_AT.store((2 * guard.len()).max(MIN_SWEEP), Ordering::Relaxed);
+        }
+        let item: Arc<str> = Arc::from(value);
+        guard.insert(item.clone());
+        Symbol(item)
+    }
+}
+
+/// Bytes held by the interned strings pool
+pub fn pool_size() -> usize {
+    POOL.read().ok().map(|guard| {
+        // two reference counters live in front of every Arc payload
+        let header = 2 * size_of::<usize>();
+        guard.capacity() * size_of::<Arc<str>>() + guard.iter().map(|i| header + i.len()).sum::<usize>()
+    }).unwrap_or(0)
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+// lets indexes keyed by symbols be searched by a plain string without interning it
+impl Borrow<str> for Symbol {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Serialize for Symbol {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D>(deserializer: D) -> Result<Symbol, D::Error>
+        where D: Deserializer<'de>
+    {
+        let value = String::deserialize(deserializer)?;
+        Ok(Symbol::new(&value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unused_strings_are_swept() {
+        let kept = Symbol::new("kept by the test");
+        for i in 0..10 * MIN_SWEEP {
+            Symbol::new(&format!("unused {}", i));
+        }
+        let guard = POOL.read().unwrap();
+        assert!(guard.len() <= 2 * MIN_SWEEP, "{} strings in the pool", guard.len());
+        assert!(guard.contains("kept by the test"));
+        assert_eq!(&*kept, "kept by the test");
+    }
+}
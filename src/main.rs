@@ -1,6 +1,6 @@
 #[macro_use] extern crate lazy_static;
 
-// extern crate serde;
+extern crate serde;
 extern crate serde_json;
 
 #[macro_use]
@@ -16,23 +16,47 @@ extern crate tokio_proto;
 extern crate tokio_minihttp;
 extern crate chrono;
 
+mod age;
+mod config;
+mod decimal;
+mod entity;
+mod error;
+mod filter;
+mod index;
+mod intern;
+mod memory;
+mod query;
+mod validate;
+
 use std::{env, fs};
-use std::process::Command;
 use futures::future;
 use tokio_service::Service;
 use tokio_proto::TcpServer;
 use tokio_minihttp::{Request, Response, Http};
-use regex::Regex;
+use regex::{Regex, Captures};
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeMap;
 use std::thread;
 use std::fs::File;
 use std::time::Instant;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use threadpool::ThreadPool;
-use std::sync::{Arc, RwLock};
-use std::collections::BTreeMap;
-
-use chrono::prelude::*;
-use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::process;
+
+
+use age::Clock;
+use config::{Config, OrphanPolicy};
+use decimal::Precision;
+use entity::{Keyed, Entity, PartialEntity, Field, Repository, find_null, parse_entity, parse_partial};
+use error::ApiError;
+use filter::{Alias, FieldSpec, FieldValue, Filterable, Kind, Op, Schema, Value};
+use index::{Aggregate, MultiIndex, OrderedIndex, Rank, Ranking, UniqueIndex};
+use intern::Symbol;
+use memory::{HeapSize, MapUsage, map_usage, show_map_usage};
+use query::{Cursor, Order, Page, Params};
+use validate::Validate;
 
 // TODO smart partial updates
 
@@ -41,40 +65,129 @@ lazy_static! {
     static ref LOCATION_FILE_RE: Regex = Regex::new(r"locations_\d+.json$").unwrap();
     static ref VISITS_FILE_RE: Regex = Regex::new(r"visits_\d+.json$").unwrap();
 
+    static ref USERS_RE: Regex = Regex::new(r"^/users(\?.*)?$").unwrap();
+    static ref LOCATIONS_RE: Regex = Regex::new(r"^/locations(\?.*)?$").unwrap();
+    static ref LOCATIONS_TOP_RE: Regex = Regex::new(r"^/locations/top(\?.*)?$").unwrap();
+
     static ref USER_RE: Regex = Regex::new(r"^/users/(?P<id>\d+)[\?]*").unwrap();
     static ref LOCATION_RE: Regex = Regex::new(r"^/locations/(?P<id>\d+)[\?]*").unwrap();
     static ref VISITS_RE: Regex = Regex::new(r"^/visits/(?P<id>\d+)[\?]*").unwrap();
 
     static ref USER_VISITS_RE: Regex = Regex::new(r"^/users/(?P<id>\d+)/visits[\?]*.*").unwrap();
+    static ref USER_STATS_RE: Regex = Regex::new(r"^/users/(?P<id>\d+)/stats[\?]*.*").unwrap();
     static ref LOCATION_MARKS_RE: Regex = Regex::new(r"^/locations/(?P<id>\d+)/avg[\?]*.*").unwrap();
+    static ref LOCATION_VISITS_RE: Regex = Regex::new(r"^/locations/(?P<id>\d+)/visits[\?]*.*").unwrap();
+    static ref LOCATION_STATS_RE: Regex = Regex::new(r"^/locations/(?P<id>\d+)/stats[\?]*.*").unwrap();
+    static ref LOCATION_TREND_RE: Regex = Regex::new(r"^/locations/(?P<id>\d+)/trend[\?]*.*").unwrap();
 
     static ref USER_NEW_RE: Regex = Regex::new(r"^/users/new[\?]*").unwrap();
     static ref LOCATION_NEW_RE: Regex = Regex::new(r"^/locations/new[\?]*").unwrap();
     static ref VISITS_NEW_RE: Regex = Regex::new(r"^/visits/new[\?]*").unwrap();
+
+    static ref COUNTRIES_RE: Regex = Regex::new(r"^/countries(\?.*)?$").unwrap();
+    static ref COUNTRY_AVG_RE: Regex = Regex::new(r"^/countries/(?P<name>[^/?]+)/avg[\?]*.*").unwrap();
+
+    static ref ADMIN_MEMORY_RE: Regex = Regex::new(r"^/admin/memory[\?]*").unwrap();
 }
 
+// limits of the entity fields
+const NAME_MAX_LENGTH: usize = 50;
+const EMAIL_MAX_LENGTH: usize = 100;
+const COUNTRY_MAX_LENGTH: usize = 50;
+const CITY_MAX_LENGTH: usize = 50;
+const PLACE_MAX_LENGTH: usize = 1000;
+const MARK_MIN: i8 = 0;
+const MARK_MAX: i8 = 5;
+// 01.01.1930 - 01.01.1999
+const BIRTH_DATE_MIN: i64 = -1262304000;
+const BIRTH_DATE_MAX: i64 = 915148800;
+// 01.01.2000 - 01.01.2015
+const VISITED_AT_MIN: i64 = 946684800;
+const VISITED_AT_MAX: i64 = 1420070400;
+
 #[derive(Serialize, Deserialize)]
 struct Users {
     users: Vec<User>,
 }
 
+#[derive(Serialize)]
+struct UsersPage {
+    users: Vec<User>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct User {
     id: i32,
     first_name: String,
     last_name: String,
-    gender: String,
+    gender: Gender,
     birth_date: i64,
     email: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 struct UserPartial {
-    first_name: Option<String>,
-    last_name: Option<String>,
-    gender: Option<String>,
-    birth_date: Option<i64>,
-    email: Option<String>,
+    first_name: Field<String>,
+    last_name: Field<String>,
+    gender: Field<Gender>,
+    birth_date: Field<i64>,
+    email: Field<String>,
+}
+
+impl Keyed for User {
+    fn key(&self) -> i32 {
+        self.id
+    }
+}
+
+impl Validate for User {
+    fn validate(&self) -> Result<(), ApiError> {
+        validate::max_length("first_name", &self.first_name, NAME_MAX_LENGTH)?;
+        validate::max_length("last_name", &self.last_name, NAME_MAX_LENGTH)?;
+        validate::email("email", &self.email, EMAIL_MAX_LENGTH)?;
+        validate::range("birth_date", self.birth_date, BIRTH_DATE_MIN, BIRTH_DATE_MAX)
+    }
+}
+
+impl Entity for User {
+    type Partial = UserPartial;
+
+    const FIELDS: &'static [&'static str] = &["id", "first_name", "last_name", "gender", "birth_date", "email"];
+
+    const CHOICES: &'static [(&'static str, &'static [&'static str])] = &[("gender", &["m", "f"])];
+
+    fn merge(&mut self, partial: UserPartial) {
+        if let Field::Value(first_name) = partial.first_name {
+            self.first_name = first_name;
+        }
+        if let Field::Value(last_name) = partial.last_name {
+            self.last_name = last_name;
+        }
+        if let Field::Value(birth_date) = partial.birth_date {
+            self.birth_date = birth_date;
+        }
+        if let Field::Value(gender) = partial.gender {
+            self.gender = gender;
+        }
+        if let Field::Value(email) = partial.email {
+            self.email = email;
+        }
+    }
+}
+
+impl PartialEntity for UserPartial {
+    fn null_field(&self) -> Option<&'static str> {
+        find_null(&[
+            ("first_name", self.first_name.is_null()),
+            ("last_name", self.last_name.is_null()),
+            ("birth_date", self.birth_date.is_null()),
+            ("email", self.email.is_null()),
+            ("gender", self.gender.is_null()),
+        ])
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -82,21 +195,76 @@ struct Locations {
     locations: Vec<Location>,
 }
 
+#[derive(Serialize)]
+struct LocationsPage {
+    locations: Vec<Location>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 struct Location {
     id: i32,
     distance: i32,
-    city: String,
-    place: String,
-    country: String,
+    city: Symbol,
+    place: Symbol,
+    country: Symbol,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 struct LocationPartial {
-    distance: Option<i32>,
-    city: Option<String>,
-    place: Option<String>,
-    country: Option<String>,
+    distance: Field<i32>,
+    city: Field<String>,
+    place: Field<String>,
+    country: Field<String>,
+}
+
+impl Keyed for Location {
+    fn key(&self) -> i32 {
+        self.id
+    }
+}
+
+impl Validate for Location {
+    fn validate(&self) -> Result<(), ApiError> {
+        validate::max_length("country", &self.country, COUNTRY_MAX_LENGTH)?;
+        validate::max_length("city", &self.city, CITY_MAX_LENGTH)?;
+        validate::max_length("place", &self.place, PLACE_MAX_LENGTH)?;
+        validate::range("distance", self.distance, 0, i32::MAX)
+    }
+}
+
+impl Entity for Location {
+    type Partial = LocationPartial;
+
+    const FIELDS: &'static [&'static str] = &["id", "distance", "city", "place", "country"];
+
+    fn merge(&mut self, partial: LocationPartial) {
+        if let Field::Value(distance) = partial.distance {
+            self.distance = distance;
+        }
+        if let Field::Value(ref city) = partial.city {
+            self.city = Symbol::new(city);
+        }
+        if let Field::Value(ref place) = partial.place {
+            self.place = Symbol::new(place);
+        }
+        if let Field::Value(ref country) = partial.country {
+            self.country = Symbol::new(country);
+        }
+    }
+}
+
+impl PartialEntity for LocationPartial {
+    fn null_field(&self) -> Option<&'static str> {
+        find_null(&[
+            ("distance", self.distance.is_null()),
+            ("city", self.city.is_null()),
+            ("place", self.place.is_null()),
+            ("country", self.country.is_null()),
+        ])
+    }
 }
 
 
@@ -114,26 +282,153 @@ struct Visit {
     mark: i8,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 struct VisitPartial {
-    user: Option<i32>,
-    location: Option<i32>,
-    visited_at: Option<i64>,
-    mark: Option<i8>,
+    user: Field<i32>,
+    location: Field<i32>,
+    visited_at: Field<i64>,
+    mark: Field<i8>,
 }
 
-type UserHashMap = Arc<RwLock<BTreeMap<i32, User>>>;
-type LocationHashMap = Arc<RwLock<BTreeMap<i32, Location>>>;
-type VisitHashMap = Arc<RwLock<BTreeMap<i32, Visit>>>;
-type LocationMarkListHashMap = Arc<RwLock<BTreeMap<i32, LocationMarkList>>>;
-type UserVisitListHashMap = Arc<RwLock<BTreeMap<i32, UserVisitList>>>;
+impl Keyed for Visit {
+    fn key(&self) -> i32 {
+        self.id
+    }
+}
 
+impl Validate for Visit {
+    fn validate(&self) -> Result<(), ApiError> {
+        validate::range("mark", self.mark, MARK_MIN, MARK_MAX)?;
+        validate::range("visited_at", self.visited_at, VISITED_AT_MIN, VISITED_AT_MAX)
+    }
+}
+
+impl Entity for Visit {
+    type Partial = VisitPartial;
+
+    const FIELDS: &'static [&'static str] = &["id", "user", "location", "visited_at", "mark"];
+
+    fn merge(&mut self, partial: VisitPartial) {
+        if let Field::Value(mark) = partial.mark {
+            self.mark = mark;
+        }
+        if let Field::Value(user) = partial.user {
+            self.user = user;
+        }
+        if let Field::Value(location) = partial.location {
+            self.location = location;
+        }
+        if let Field::Value(visited_at) = partial.visited_at {
+            self.visited_at = visited_at;
+        }
+    }
+}
+
+impl PartialEntity for VisitPartial {
+    fn null_field(&self) -> Option<&'static str> {
+        find_null(&[
+            ("mark", self.mark.is_null()),
+            ("user", self.user.is_null()),
+            ("location", self.location.is_null()),
+            ("visited_at", self.visited_at.is_null()),
+        ])
+    }
+}
+
+#[derive(Clone)]
 struct Travels {
-    users: UserHashMap,
-    locations: LocationHashMap,
-    visits: VisitHashMap,
-    user_visits: UserVisitListHashMap,
-    location_marks: LocationMarkListHashMap,
+    users: Repository<User>,
+    locations: Repository<Location>,
+    visits: Repository<Visit>,
+    user_visits: Repository<UserVisitList>,
+    location_marks: Repository<LocationMarkList>,
+    emails: UniqueIndex<String>,
+    user_last_names: MultiIndex<String>,
+    user_birth_dates: OrderedIndex<i64>,
+    location_countries: MultiIndex<Symbol>,
+    location_cities: MultiIndex<Symbol>,
+    countries: Aggregate<Symbol, CountryTally>,
+    /// Locations by the average mark of all their visits
+    location_ranking: Ranking,
+    config: Arc<Config>,
+}
+
+#[derive(Debug, Serialize)]
+struct MemoryReport {
+    rss: Option<usize>,
+    interned: usize,
+    users: MapUsage,
+    locations: MapUsage,
+    visits: MapUsage,
+    user_visits: MapUsage,
+    location_marks: MapUsage,
+}
+
+impl Travels {
+    /// Add visit to the user visits and location marks lists
+    fn index_visit(&self, visit: &Visit, user: &User, location: &Location) {
+        self.user_visits.update(visit.user, |user_visits| user_visits.insert(UserVisit::new(visit, location)));
+        self.location_marks.update(visit.location, |location_marks| {
+            let before = (location_marks.mark_sum, location_marks.marks.len() as i64);
+            location_marks.insert(LocationMark::new(visit, user));
+            self.count_country_marks(&location.country, location_marks, before);
+            self.location_ranking.set(location.id, location_marks.mark_sum, location_marks.marks.len() as i64);
+        });
+    }
+
+    /// Remove visit from the user visits and location marks lists
+    fn unindex_visit(&self, visit: &Visit) {
+        self.user_visits.update(visit.user, |user_visits| user_visits.remove(visit.visited_at, visit.id));
+        let country = self.locations.load(visit.location).map(|location| location.country);
+        self.location_marks.update(visit.location, |location_marks| {
+            let before = (location_marks.mark_sum, location_marks.marks.len() as i64);
+            if location_marks.remove(visit.visited_at, visit.id).is_some() {
+                if let Some(ref country) = country {
+                    self.count_country_marks(country, location_marks, before);
+                }
+                self.location_ranking.set(visit.location, location_marks.mark_sum, location_marks.marks.len() as i64);
+            }
+        });
+    }
+
+    /// Apply the change of location marks from the (sum, count) before it to the country totals,
+    /// called while the marks are locked so that concurrent changes add up
+    fn count_country_marks(&self, country: &Symbol, marks: &LocationMarkList, before: (i64, i64)) {
+        let (sum, count) = (marks.mark_sum - before.0, marks.marks.len() as i64 - before.1);
+        if sum != 0 || count != 0 {
+            self.countries.update(country.clone(), |t| {
+                t.mark_sum += sum;
+                t.visits += count;
+            });
+        }
+    }
+
+    fn memory_report(&self) -> MemoryReport {
+        MemoryReport {
+            rss: memory::rss_bytes(),
+            interned: intern::pool_size(),
+            users: map_usage(&self.users.read()),
+            locations: map_usage(&self.locations.read()),
+            visits: map_usage(&self.visits.read()),
+            user_visits: map_usage(&self.user_visits.read()),
+            location_marks: map_usage(&self.location_marks.read()),
+        }
+    }
+}
+
+fn show_memory_usage(report: &MemoryReport) {
+    println!("-----------------");
+    match report.rss {
+        Some(rss) => println!("rss: {} Mb", rss / 1024 / 1024),
+        None => println!("rss: unknown"),
+    }
+    show_map_usage("users", report.users);
+    show_map_usage("locations", report.locations);
+    show_map_usage("visits", report.visits);
+    show_map_usage("user visits", report.user_visits);
+    show_map_usage("location marks", report.location_marks);
+    println!("interned strings: {} bytes", report.interned);
 }
 
 #[derive(Debug, Deserialize)]
@@ -146,7 +441,8 @@ struct BodyLength {
 struct UserVisit {
     // search fields
     distance: i32,
-    country: String,
+    country: Symbol,
+    city: Symbol,
     location: i32,
     visit: i32,
     user: i32,
@@ -155,11 +451,33 @@ struct UserVisit {
     body: UserVisitBody,
 }
 
+impl UserVisit {
+    fn cursor(&self) -> Cursor {
+        Cursor(self.body.visited_at, self.visit)
+    }
+
+    fn new(visit: &Visit, location: &Location) -> UserVisit {
+        UserVisit {
+            user: visit.user,
+            visit: visit.id,
+            location: location.id,
+            distance: location.distance,
+            country: location.country.clone(),
+            city: location.city.clone(),
+            body: UserVisitBody {
+                visited_at: visit.visited_at,
+                mark: visit.mark,
+                place: location.place.clone(),
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct UserVisitBody {
     mark: i8,
     visited_at: i64,
-    place: String,
+    place: Symbol,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -168,9 +486,171 @@ struct UserVisitList {
     visits: Vec<UserVisit>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct UserVisitResponse {
-    visits: Vec<UserVisitBody>,
+impl Keyed for UserVisitList {
+    fn key(&self) -> i32 {
+        self.user
+    }
+}
+
+impl UserVisitList {
+    fn new(user: i32) -> UserVisitList {
+        UserVisitList { user, visits: Vec::new() }
+    }
+
+    // visits are kept ordered by (visited_at, visit id)
+    fn search(&self, visited_at: i64, visit: i32) -> Result<usize, usize> {
+        self.visits.binary_search_by(|i| (i.body.visited_at, i.visit).cmp(&(visited_at, visit)))
+    }
+
+    fn insert(&mut self, item: UserVisit) {
+        match self.search(item.body.visited_at, item.visit) {
+            Ok(index) => self.visits[index] = item,
+            Err(index) => self.visits.insert(index, item),
+        }
+    }
+
+    fn remove(&mut self, visited_at: i64, visit: i32) -> Option<UserVisit> {
+        match self.search(visited_at, visit) {
+            Ok(index) => Some(self.visits.remove(index)),
+            Err(_) => None,
+        }
+    }
+
+    /// Visits following the page cursor in the page order
+    fn range(&self, page: &Page) -> &[UserVisit] {
+        match (page.after, page.order) {
+            (Some(after), Order::Asc) => &self.visits[self.visits.partition_point(|i| i.cursor() <= after)..],
+            (Some(after), Order::Desc) => &self.visits[..self.visits.partition_point(|i| i.cursor() < after)],
+            (None, _) => &self.visits,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct VisitsResponse<T> {
+    visits: Vec<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+}
+
+/// Summary of user visits, empty selection has no average mark and visit dates
+#[derive(Debug, Serialize)]
+struct UserStats {
+    visits: usize,
+    countries: usize,
+    locations: usize,
+    avg_mark: Option<f64>,
+    /// Sum of distances of the visited locations
+    distance: i64,
+    first_visited_at: Option<i64>,
+    last_visited_at: Option<i64>,
+}
+
+impl UserStats {
+    fn new(visits: &[&UserVisit], precision: &Precision) -> UserStats {
+        let count = visits.len();
+        let mark_sum = visits.iter().map(|v| v.body.mark as i64).sum::<i64>();
+        UserStats {
+            visits: count,
+            countries: visits.iter().map(|v| &v.country).collect::<HashSet<_>>().len(),
+            locations: visits.iter().map(|v| v.location).collect::<HashSet<_>>().len(),
+            avg_mark: if count > 0 { Some(decimal::round_ratio(mark_sum, count as i64, precision)) } else { None },
+            distance: visits.iter().map(|v| v.distance as i64).sum(),
+            // visits are ordered by visited_at
+            first_visited_at: visits.first().map(|v| v.body.visited_at),
+            last_visited_at: visits.last().map(|v| v.body.visited_at),
+        }
+    }
+}
+
+/// Denormalized user visit fields which could be selected by `fields=` or `expand=location`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UserVisitField {
+    Visit,
+    Location,
+    City,
+    Country,
+    Distance,
+    Mark,
+    VisitedAt,
+    Place,
+}
+
+const USER_VISIT_FIELDS: [UserVisitField; 8] = [
+    UserVisitField::Visit,
+    UserVisitField::Location,
+    UserVisitField::City,
+    UserVisitField::Country,
+    UserVisitField::Distance,
+    UserVisitField::Mark,
+    UserVisitField::VisitedAt,
+    UserVisitField::Place,
+];
+
+impl UserVisitField {
+    fn name(self) -> &'static str {
+        match self {
+            UserVisitField::Visit => "visit",
+            UserVisitField::Location => "location",
+            UserVisitField::City => "city",
+            UserVisitField::Country => "country",
+            UserVisitField::Distance => "distance",
+            UserVisitField::Mark => "mark",
+            UserVisitField::VisitedAt => "visited_at",
+            UserVisitField::Place => "place",
+        }
+    }
+
+    fn serialize_entry<M: SerializeMap>(self, map: &mut M, v: &UserVisit) -> Result<(), M::Error> {
+        match self {
+            UserVisitField::Visit => map.serialize_entry(self.name(), &v.visit),
+            UserVisitField::Location => map.serialize_entry(self.name(), &v.location),
+            UserVisitField::City => map.serialize_entry(self.name(), &v.city),
+            UserVisitField::Country => map.serialize_entry(self.name(), &v.country),
+            UserVisitField::Distance => map.serialize_entry(self.name(), &v.distance),
+            UserVisitField::Mark => map.serialize_entry(self.name(), &v.body.mark),
+            UserVisitField::VisitedAt => map.serialize_entry(self.name(), &v.body.visited_at),
+            UserVisitField::Place => map.serialize_entry(self.name(), &v.body.place),
+        }
+    }
+}
+
+/// User visit with only the selected fields in the requested order
+struct SelectedFields<'a> {
+    fields: &'a [UserVisitField],
+    visit: &'a UserVisit,
+}
+
+impl<'a> Serialize for SelectedFields<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.fields.len()))?;
+        for field in self.fields {
+            field.serialize_entry(&mut map, self.visit)?;
+        }
+        map.end()
+    }
+}
+
+/// Selected fields, `None` stands for the default `UserVisitBody` shape
+fn parse_user_visit_fields(query: &str) -> Result<Option<Vec<UserVisitField>>, ApiError> {
+    let mut fields: Option<Vec<UserVisitField>> = None;
+    for (key, value) in query::pairs(query) {
+        let names = match key {
+            "fields" => query::decode_value(key, value)?,
+            "expand" if value == "location" => "mark,visited_at,place,visit,location,city,country,distance".to_string(),
+            "expand" => return Err(ApiError::malformed_field(key, "only location could be expanded")),
+            _ => continue,
+        };
+        let selected = fields.get_or_insert_with(Vec::new);
+        for name in names.split(',') {
+            let field = USER_VISIT_FIELDS.iter().find(|f| f.name() == name)
+                .ok_or_else(|| ApiError::malformed_field(key, format!("unknown field {}", name)))?;
+            if !selected.contains(field) {
+                selected.push(*field);
+            }
+        }
+    }
+    Ok(fields)
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -185,1023 +665,1031 @@ struct LocationMark {
     visit: i32,
 }
 
+impl LocationMark {
+    fn cursor(&self) -> Cursor {
+        Cursor(self.visited_at, self.visit)
+    }
+
+    fn new(visit: &Visit, user: &User) -> LocationMark {
+        LocationMark {
+            user: user.id,
+            visit: visit.id,
+            gender: user.gender,
+            birth_date: user.birth_date,
+            mark: visit.mark,
+            visited_at: visit.visited_at,
+        }
+    }
+}
+
+/// Visit of a location as listed by `/locations/<id>/visits`
+#[derive(Debug, Serialize)]
+struct LocationVisit {
+    visit: i32,
+    user: i32,
+    mark: i8,
+    visited_at: i64,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct LocationMarkList {
     location: i32,
     marks: Vec<LocationMark>,
+    /// Sum of all marks, kept in step with `marks` for unfiltered rankings
+    mark_sum: i64,
+}
+
+impl Keyed for LocationMarkList {
+    fn key(&self) -> i32 {
+        self.location
+    }
+}
+
+impl LocationMarkList {
+    fn new(location: i32) -> LocationMarkList {
+        LocationMarkList { location, marks: Vec::new(), mark_sum: 0 }
+    }
+
+    // marks are kept ordered by (visited_at, visit id)
+    fn search(&self, visited_at: i64, visit: i32) -> Result<usize, usize> {
+        self.marks.binary_search_by(|i| (i.visited_at, i.visit).cmp(&(visited_at, visit)))
+    }
+
+    fn insert(&mut self, mark: LocationMark) {
+        self.mark_sum += mark.mark as i64;
+        match self.search(mark.visited_at, mark.visit) {
+            Ok(index) => {
+                self.mark_sum -= self.marks[index].mark as i64;
+                self.marks[index] = mark;
+            },
+            Err(index) => self.marks.insert(index, mark),
+        }
+    }
+
+    fn remove(&mut self, visited_at: i64, visit: i32) -> Option<LocationMark> {
+        let index = self.search(visited_at, visit).ok()?;
+        let mark = self.marks.remove(index);
+        self.mark_sum -= mark.mark as i64;
+        Some(mark)
+    }
+
+    /// Marks following the page cursor in the page order
+    fn range(&self, page: &Page) -> &[LocationMark] {
+        match (page.after, page.order) {
+            (Some(after), Order::Asc) => &self.marks[self.marks.partition_point(|i| i.cursor() <= after)..],
+            (Some(after), Order::Desc) => &self.marks[..self.marks.partition_point(|i| i.cursor() < after)],
+            (None, _) => &self.marks,
+        }
+    }
 }
 
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 enum Gender {
+    #[serde(rename = "m")]
     MALE,
+    #[serde(rename = "f")]
     FEMALE,
 }
 
-struct SumCount {
-    sum: i32,
-    count: i32,
+impl Gender {
+    fn code(self) -> &'static str {
+        match self {
+            Gender::MALE => "m",
+            Gender::FEMALE => "f",
+        }
+    }
 }
 
-#[derive(Debug)]
-enum QueryField {
-    FromDate,
-    ToDate,
-    FromAge,
-    ToAge,
-    Country,
-    ToDistance,
-    Gender,
-    Other,
+// interned strings are accounted by the pool
+impl HeapSize for User {
+    fn heap_size(&self) -> usize {
+        self.first_name.heap_size() + self.last_name.heap_size() + self.email.heap_size()
+    }
 }
 
-#[derive(Debug)]
-struct QueryFilter {
-    key: QueryField,
-    value_i32: Option<i32>,
-    value_i64: Option<i64>,
-    value_str: Option<String>,
-    value_gender: Option<Gender>,
-    value_dt: Option<DateTime<Utc>>,
+impl HeapSize for Location {
+    fn heap_size(&self) -> usize {
+        0
+    }
 }
 
-
-fn exists_user(id: i32, items: &UserHashMap) -> bool {
-    items.read().ok().and_then(|guard| Some(guard.contains_key(&id))).unwrap_or(false)
+impl HeapSize for Visit {
+    fn heap_size(&self) -> usize {
+        0
+    }
 }
 
-fn save_user(item: User, items: &UserHashMap) {
-    items.write().ok().and_then(|mut guard| { guard.insert(item.id, item) });
+impl HeapSize for UserVisit {
+    fn heap_size(&self) -> usize {
+        0
+    }
 }
 
-fn load_user(id: i32, items: &UserHashMap) -> Option<User> {
-    items.read().ok().and_then(|guard| guard.get(&id).map(|val| val.clone()))
+impl HeapSize for UserVisitList {
+    fn heap_size(&self) -> usize {
+        self.visits.heap_size()
+    }
 }
 
-fn content_user<'a>(id: i32, items: &UserHashMap) -> Option<String> {
-    items.read().ok().and_then(|guard| guard.get(&id).map(|val| serde_json::to_string(val).unwrap_or("".to_string())))
+impl HeapSize for LocationMark {
+    fn heap_size(&self) -> usize {
+        0
+    }
 }
 
-fn exists_location(id: i32, items: &LocationHashMap) -> bool {
-    items.read().ok().and_then(|guard| Some(guard.contains_key(&id))).unwrap_or(false)
+impl HeapSize for LocationMarkList {
+    fn heap_size(&self) -> usize {
+        self.marks.heap_size()
+    }
 }
 
-fn save_location(item: Location, items: &LocationHashMap) {
-    items.write().ok().and_then(|mut guard| { guard.insert(item.id, item) });
+struct SumCount {
+    sum: i64,
+    count: i64,
 }
 
-fn load_location(id: i32, items: &LocationHashMap) -> Option<Location> {
-    items.read().ok().and_then(|guard| guard.get(&id).map(|val| val.clone()))
+/// Totals of a country maintained on location and visit writes
+#[derive(Debug, Default, Clone, PartialEq)]
+struct CountryTally {
+    locations: i64,
+    visits: i64,
+    mark_sum: i64,
 }
 
-fn content_location<'a>(id: i32, items: &LocationHashMap) -> Option<String> {
-    items.read().ok().and_then(|guard| guard.get(&id).map(|val| serde_json::to_string(val).unwrap_or("".to_string())))
+#[derive(Debug, Serialize)]
+struct CountrySummary {
+    country: Symbol,
+    locations: i64,
+    visits: i64,
+    avg: f64,
 }
 
-fn exists_visit(id: i32, items: &VisitHashMap) -> bool {
-    items.read().ok().and_then(|guard| Some(guard.contains_key(&id))).unwrap_or(false)
+#[derive(Debug, Serialize)]
+struct CountriesResponse {
+    countries: Vec<CountrySummary>,
 }
 
-fn save_visit(item: Visit, items: &VisitHashMap) {
-    items.write().ok().and_then(|mut guard| { guard.insert(item.id, item) });
+/// Distribution of location marks, empty selection has no min, max, median and stddev
+#[derive(Debug, Serialize)]
+struct LocationStats {
+    count: usize,
+    min: Option<i8>,
+    max: Option<i8>,
+    median: Option<f64>,
+    stddev: Option<f64>,
+    /// Number of marks for every value from 0 to 5
+    histogram: [usize; 6],
 }
 
-fn load_visit(id: i32, items: &VisitHashMap) -> Option<Visit> {
-    items.read().ok().and_then(|guard| guard.get(&id).map(|val| val.clone()))
+/// Marks of a location visited within one calendar period
+#[derive(Debug, Serialize)]
+struct TrendBucket {
+    start: i64,
+    avg: f64,
+    count: i64,
 }
 
-fn content_visit<'a>(id: i32, items: &VisitHashMap) -> Option<String> {
-    items.read().ok().and_then(|guard| guard.get(&id).map(|val| serde_json::to_string(val).unwrap_or("".to_string())))
+#[derive(Debug, Serialize)]
+struct TrendResponse {
+    buckets: Vec<TrendBucket>,
 }
 
-fn save_location_mark_list(item: LocationMarkList, items: &LocationMarkListHashMap) {
-    items.write().ok().and_then(|mut guard| { guard.insert(item.location, item) });
+#[derive(Debug, Serialize)]
+struct RatedLocation {
+    id: i32,
+    avg: f64,
+    count: i64,
 }
 
-fn load_location_mark_list(id: i32, items: &LocationMarkListHashMap) -> Option<LocationMarkList> {
-    items.read().ok().and_then(|guard| guard.get(&id).map(|val| val.clone()))
+#[derive(Debug, Serialize)]
+struct TopLocationsResponse {
+    locations: Vec<RatedLocation>,
 }
 
-fn save_user_visits(item: UserVisitList, items: &UserVisitListHashMap) {
-    items.write().ok().and_then(|mut guard| { guard.insert(item.user, item) });
+impl LocationStats {
+    fn new(mut marks: Vec<i8>, precision: &Precision) -> LocationStats {
+        marks.sort();
+        let count = marks.len();
+        let mut histogram = [0; 6];
+        for &mark in &marks {
+            // loaded visits are not validated, out of range marks are left out of the histogram
+            if let Some(slot) = histogram.get_mut(mark as usize) {
+                *slot += 1;
+            }
+        }
+        if count == 0 {
+            return LocationStats { count, min: None, max: None, median: None, stddev: None, histogram };
+        }
+
+        let median = if count.is_multiple_of(2) {
+            decimal::round_ratio(marks[count / 2 - 1] as i64 + marks[count / 2] as i64, 2, precision)
+        } else {
+            marks[count / 2] as f64
+        };
+        let mean = marks.iter().map(|&m| m as f64).sum::<f64>() / count as f64;
+        let variance = marks.iter().map(|&m| (m as f64 - mean).powi(2)).sum::<f64>() / count as f64;
+
+        LocationStats {
+            count,
+            min: marks.first().cloned(),
+            max: marks.last().cloned(),
+            median: Some(median),
+            stddev: Some(decimal::round_float(variance.sqrt(), precision)),
+            histogram,
+        }
+    }
 }
 
-fn load_user_visits(id: i32, items: &UserVisitListHashMap) -> Option<UserVisitList> {
-    items.read().ok().and_then(|guard| guard.get(&id).map(|val| val.clone()))
+const INT_OPS: &[Op] = &[Op::Eq, Op::Lt, Op::Lte, Op::Gt, Op::Gte, Op::In];
+const STR_OPS: &[Op] = &[Op::Eq, Op::In, Op::Prefix];
+
+// bounds of the aliases are exclusive
+static USER_VISIT_FILTERS: Schema = Schema {
+    fields: &[
+        FieldSpec { name: "visited_at", kind: Kind::Int, ops: INT_OPS },
+        FieldSpec { name: "distance", kind: Kind::Int, ops: INT_OPS },
+        FieldSpec { name: "mark", kind: Kind::Int, ops: INT_OPS },
+        FieldSpec { name: "location", kind: Kind::Int, ops: INT_OPS },
+        FieldSpec { name: "country", kind: Kind::Str, ops: STR_OPS },
+        FieldSpec { name: "city", kind: Kind::Str, ops: STR_OPS },
+        FieldSpec { name: "place", kind: Kind::Str, ops: STR_OPS },
+    ],
+    aliases: &[
+        Alias { param: "fromDate", field: "visited_at", op: Op::Gt, map: None },
+        Alias { param: "toDate", field: "visited_at", op: Op::Lt, map: None },
+        Alias { param: "fromDistance", field: "distance", op: Op::Gt, map: None },
+        Alias { param: "toDistance", field: "distance", op: Op::Lt, map: None },
+        Alias { param: "fromMark", field: "mark", op: Op::Gt, map: None },
+        Alias { param: "toMark", field: "mark", op: Op::Lt, map: None },
+        Alias { param: "placePrefix", field: "place", op: Op::Prefix, map: None },
+    ],
+};
+
+const GENDER: Kind = Kind::OneOf(&["m", "f"]);
+
+// at least fromAge years old means born before the bound, younger than toAge since it
+const FROM_AGE: Alias = Alias { param: "fromAge", field: "birth_date", op: Op::Lt, map: Some(age::born_by) };
+const TO_AGE: Alias = Alias { param: "toAge", field: "birth_date", op: Op::Gte, map: Some(age::born_by) };
+
+static LOCATION_MARK_FILTERS: Schema = Schema {
+    fields: &[
+        FieldSpec { name: "visited_at", kind: Kind::Int, ops: INT_OPS },
+        FieldSpec { name: "birth_date", kind: Kind::Int, ops: INT_OPS },
+        FieldSpec { name: "mark", kind: Kind::Int, ops: INT_OPS },
+        FieldSpec { name: "user", kind: Kind::Int, ops: INT_OPS },
+        FieldSpec { name: "gender", kind: GENDER, ops: &[Op::Eq, Op::In] },
+    ],
+    aliases: &[
+        Alias { param: "fromDate", field: "visited_at", op: Op::Gt, map: None },
+        Alias { param: "toDate", field: "visited_at", op: Op::Lt, map: None },
+        FROM_AGE,
+        TO_AGE,
+    ],
+};
+
+/// Mark filters of the ranked locations along with their country and city
+static TOP_LOCATION_FILTERS: Schema = Schema {
+    fields: &[
+        FieldSpec { name: "visited_at", kind: Kind::Int, ops: INT_OPS },
+        FieldSpec { name: "birth_date", kind: Kind::Int, ops: INT_OPS },
+        FieldSpec { name: "mark", kind: Kind::Int, ops: INT_OPS },
+        FieldSpec { name: "user", kind: Kind::Int, ops: INT_OPS },
+        FieldSpec { name: "gender", kind: GENDER, ops: &[Op::Eq, Op::In] },
+        FieldSpec { name: "country", kind: Kind::Str, ops: &[Op::Eq, Op::In] },
+        FieldSpec { name: "city", kind: Kind::Str, ops: &[Op::Eq, Op::In] },
+    ],
+    aliases: &[
+        Alias { param: "fromDate", field: "visited_at", op: Op::Gt, map: None },
+        Alias { param: "toDate", field: "visited_at", op: Op::Lt, map: None },
+        FROM_AGE,
+        TO_AGE,
+    ],
+};
+
+/// Location fields of the ranked locations, the rest of their filters are applied to the marks
+const PLACE_FIELDS: &[&str] = &["country", "city"];
+
+static USER_FILTERS: Schema = Schema {
+    fields: &[
+        FieldSpec { name: "email", kind: Kind::Str, ops: STR_OPS },
+        FieldSpec { name: "last_name", kind: Kind::Str, ops: STR_OPS },
+        FieldSpec { name: "first_name", kind: Kind::Str, ops: STR_OPS },
+        FieldSpec { name: "gender", kind: GENDER, ops: &[Op::Eq, Op::In] },
+        FieldSpec { name: "birth_date", kind: Kind::Int, ops: INT_OPS },
+    ],
+    aliases: &[
+        Alias { param: "first_name_prefix", field: "first_name", op: Op::Prefix, map: None },
+        FROM_AGE,
+        TO_AGE,
+    ],
+};
+
+// bounds of the aliases are exclusive
+static LOCATION_FILTERS: Schema = Schema {
+    fields: &[
+        FieldSpec { name: "country", kind: Kind::Str, ops: STR_OPS },
+        FieldSpec { name: "city", kind: Kind::Str, ops: STR_OPS },
+        FieldSpec { name: "place", kind: Kind::Str, ops: STR_OPS },
+        FieldSpec { name: "distance", kind: Kind::Int, ops: INT_OPS },
+    ],
+    aliases: &[
+        Alias { param: "place_prefix", field: "place", op: Op::Prefix, map: None },
+        Alias { param: "fromDistance", field: "distance", op: Op::Gt, map: None },
+        Alias { param: "toDistance", field: "distance", op: Op::Lt, map: None },
+    ],
+};
+
+/// Page size of the user and location searches without `limit` and its maximum
+const SEARCH_LIMIT: usize = 100;
+const MAX_SEARCH_LIMIT: usize = 1000;
+
+static USERS_PARAMS: Params = Params {
+    single: &["limit", "offset", "after", "order"],
+    repeated: &[],
+    filters: Some(&USER_FILTERS),
+};
+
+static LOCATIONS_PARAMS: Params = Params {
+    single: &["limit", "offset", "after", "order"],
+    repeated: &[],
+    filters: Some(&LOCATION_FILTERS),
+};
+
+static USER_VISITS_PARAMS: Params = Params {
+    single: &["limit", "offset", "after", "order"],
+    repeated: &["fields", "expand"],
+    filters: Some(&USER_VISIT_FILTERS),
+};
+
+static USER_STATS_PARAMS: Params = Params { single: &[], repeated: &[], filters: Some(&USER_VISIT_FILTERS) };
+
+static LOCATION_MARKS_PARAMS: Params = Params { single: &[], repeated: &[], filters: Some(&LOCATION_MARK_FILTERS) };
+
+static LOCATION_VISITS_PARAMS: Params = Params {
+    single: &["limit", "offset", "after", "order"],
+    repeated: &[],
+    filters: Some(&LOCATION_MARK_FILTERS),
+};
+
+static LOCATION_TREND_PARAMS: Params = Params { single: &["bucket"], repeated: &[], filters: Some(&LOCATION_MARK_FILTERS) };
+
+static TOP_LOCATIONS_PARAMS: Params = Params {
+    single: &["limit", "minCount"],
+    repeated: &[],
+    filters: Some(&TOP_LOCATION_FILTERS),
+};
+
+static NO_PARAMS: Params = Params { single: &[], repeated: &[], filters: None };
+
+impl Filterable for UserVisit {
+    fn field(&self, name: &str) -> Option<FieldValue<'_>> {
+        match name {
+            "visited_at" => Some(FieldValue::Int(self.body.visited_at)),
+            "distance" => Some(FieldValue::Int(self.distance as i64)),
+            "mark" => Some(FieldValue::Int(self.body.mark as i64)),
+            "location" => Some(FieldValue::Int(self.location as i64)),
+            "country" => Some(FieldValue::Str(&self.country)),
+            "city" => Some(FieldValue::Str(&self.city)),
+            "place" => Some(FieldValue::Str(&self.body.place)),
+            _ => None,
+        }
+    }
 }
 
-impl Service for Travels {
-    type Request = Request;
-    type Response = Response;
-    type Error = std::io::Error;
-    type Future = future::Ok<Response, std::io::Error>;
+impl Filterable for User {
+    fn field(&self, name: &str) -> Option<FieldValue<'_>> {
+        match name {
+            "email" => Some(FieldValue::Str(&self.email)),
+            "last_name" => Some(FieldValue::Str(&self.last_name)),
+            "first_name" => Some(FieldValue::Str(&self.first_name)),
+            "gender" => Some(FieldValue::Str(self.gender.code())),
+            "birth_date" => Some(FieldValue::Int(self.birth_date)),
+            _ => None,
+        }
+    }
+}
 
-    fn call(&self, req: Request) -> Self::Future {
+impl Filterable for Location {
+    fn field(&self, name: &str) -> Option<FieldValue<'_>> {
+        match name {
+            "country" => Some(FieldValue::Str(&self.country)),
+            "city" => Some(FieldValue::Str(&self.city)),
+            "place" => Some(FieldValue::Str(&self.place)),
+            "distance" => Some(FieldValue::Int(self.distance as i64)),
+            _ => None,
+        }
+    }
+}
 
-        let mut resp = Response::new();
+impl Filterable for LocationMark {
+    fn field(&self, name: &str) -> Option<FieldValue<'_>> {
+        match name {
+            "visited_at" => Some(FieldValue::Int(self.visited_at)),
+            "birth_date" => Some(FieldValue::Int(self.birth_date)),
+            "mark" => Some(FieldValue::Int(self.mark as i64)),
+            "user" => Some(FieldValue::Int(self.user as i64)),
+            "gender" => Some(FieldValue::Str(self.gender.code())),
+            _ => None,
+        }
+    }
+}
 
-        let mut found = false;
+impl Travels {
+    fn route(&self, req: &Request) -> Result<String, ApiError> {
         let path = req.path();
+        let query = path.split('?').nth(1);
         let is_post = req.method() == "POST";
 
-        if let Some(cap) = USER_RE.captures(path) {
-            if is_post {
-                let id = cap.name("id").map_or("", |m| m.as_str()).parse::<i32>().unwrap_or(-1);
-                let post_data = req.body();
-                if let Ok(user_partial) = serde_json::from_str::<UserPartial>(post_data) {
-                    // validate null fields
-                    if user_partial.first_name.is_none() {
-                        if post_data.contains("\"first_name\"") {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
-                    if user_partial.last_name.is_none() {
-                        if post_data.contains("\"last_name\"") {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
-                    if user_partial.birth_date.is_none() {
-                        if post_data.contains("\"birth_date\"") {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
-                    if user_partial.email.is_none() {
-                        if post_data.contains("\"email\"") {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
-                    if user_partial.gender.is_none() {
-                        if post_data.contains("\"gender\"") {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
+        if is_post && req.body().len() > self.config.max_body_size {
+            return Err(ApiError::PayloadTooLarge { limit: self.config.max_body_size });
+        }
 
-                    if let Some(mut user) = load_user(id, &self.users) {
-                        found = true;
-                        if let Some(ref first_name) = user_partial.first_name {
-                            user.first_name = first_name.to_string();
-                        }
-                        if let Some(ref last_name) = user_partial.last_name {
-                            user.last_name = last_name.to_string();
-                        }
-                        if let Some(ref birth_date) = user_partial.birth_date {
-                            user.birth_date = *birth_date;
-                        }
-                        if let Some(ref gender) = user_partial.gender {
-                            user.gender = gender.to_string();
-                        }
-                        if let Some(ref email) = user_partial.email {
-                            user.email = email.to_string();
-                        }
+        if let Some(cap) = USER_VISITS_RE.captures(path) {
+            return self.user_visits_content(capture_id(&cap), query);
+        }
+        if let Some(cap) = USER_STATS_RE.captures(path) {
+            return self.user_stats_content(capture_id(&cap), query);
+        }
+        if let Some(cap) = LOCATION_MARKS_RE.captures(path) {
+            return self.location_avg_content(capture_id(&cap), query);
+        }
+        if let Some(cap) = LOCATION_VISITS_RE.captures(path) {
+            return self.location_visits_content(capture_id(&cap), query);
+        }
+        if let Some(cap) = LOCATION_STATS_RE.captures(path) {
+            return self.location_stats_content(capture_id(&cap), query);
+        }
+        if let Some(cap) = LOCATION_TREND_RE.captures(path) {
+            return self.location_trend_content(capture_id(&cap), query);
+        }
+        if !is_post && USERS_RE.is_match(path) {
+            return self.users_content(query);
+        }
+        if !is_post && LOCATIONS_RE.is_match(path) {
+            return self.locations_content(query);
+        }
+        if !is_post && LOCATIONS_TOP_RE.is_match(path) {
+            return self.top_locations_content(query);
+        }
+        if !is_post && COUNTRIES_RE.is_match(path) {
+            return self.countries_content(query);
+        }
+        if let Some(cap) = COUNTRY_AVG_RE.captures(path) {
+            return self.country_avg_content(cap.name("name").map_or("", |m| m.as_str()), query);
+        }
+        if !is_post && self.config.admin_memory && ADMIN_MEMORY_RE.is_match(path) {
+            return Ok(serde_json::to_string(&self.memory_report()).unwrap_or_default());
+        }
 
+        if is_post {
+            let body = req.body();
+            if USER_NEW_RE.is_match(path) {
+                return self.create_user(body);
+            }
+            if LOCATION_NEW_RE.is_match(path) {
+                return self.create_location(body);
+            }
+            if VISITS_NEW_RE.is_match(path) {
+                return self.create_visit(body);
+            }
+            if let Some(cap) = USER_RE.captures(path) {
+                return self.update_user(capture_id(&cap), body);
+            }
+            if let Some(cap) = LOCATION_RE.captures(path) {
+                return self.update_location(capture_id(&cap), body);
+            }
+            if let Some(cap) = VISITS_RE.captures(path) {
+                return self.update_visit(capture_id(&cap), body);
+            }
+        } else {
+            if let Some(cap) = USER_RE.captures(path) {
+                return self.users.content(capture_id(&cap)).ok_or(ApiError::NotFound);
+            }
+            if let Some(cap) = LOCATION_RE.captures(path) {
+                return self.locations.content(capture_id(&cap)).ok_or(ApiError::NotFound);
+            }
+            if let Some(cap) = VISITS_RE.captures(path) {
+                return self.visits.content(capture_id(&cap)).ok_or(ApiError::NotFound);
+            }
+        }
 
-                        // update user marks
-                        if let Some(user_visits) = load_user_visits(user.id, &self.user_visits) {
-                            let locations = user_visits.visits.iter().map(|i| i.location).collect::<HashSet<_>>();
-                            for location_id in locations.iter() {
-                                if let Some(mut location_mark_list) = load_location_mark_list(*location_id, &self.location_marks) {
-                                    let mut changed = false;
-                                    for item in location_mark_list.marks.iter_mut() {
-                                        if item.user == user.id {
-                                            changed = true;
-                                            item.gender = match user.gender.as_ref() {
-                                                "m" => Gender::MALE,
-                                                _ => Gender::FEMALE,
-                                            };
-                                            item.birth_date = user.birth_date;
-                                        }
-                                    }
-
-                                    if changed {
-                                        save_location_mark_list(location_mark_list, &self.location_marks);
-                                    }
+        Err(ApiError::NotFound)
+    }
 
-                                }
-                            }
-                        }
+    /// `{"avg":..}` with the configured precision
+    fn avg_content(&self, result: &SumCount) -> String {
+        format!("{{\"avg\":{}}}", decimal::format_ratio(result.sum, result.count, &self.config.avg_precision))
+    }
 
-                        save_user(user, &self.users);
-                        resp.body(2, "{}");
-                    }
-                } else {
-                    resp.status_code(400, "Bad Request");
-                    return future::ok(resp);
-                }
-            } else {
-                let id = cap.name("id").map_or("", |m| m.as_str()).parse::<i32>().unwrap_or(-1);
-                if let Some(content) = content_user(id, &self.users) {
-                    found = true;
-                    resp.body(content.len(), content.as_ref());
-                }
-            }
+    fn parse_filters(&self, schema: &Schema, query: &str) -> Result<Vec<filter::Filter>, ApiError> {
+        filter::parse(schema, query, self.config.clock.now())
+    }
+
+    fn check_query(&self, query: &str, params: &Params) -> Result<(), ApiError> {
+        if self.config.strict_query {
+            return query::check_params(query, params);
         }
+        Ok(())
+    }
 
-        if let Some(cap) = LOCATION_RE.captures(&path) {
-            if is_post {
-                let id = cap.name("id").map_or("", |m| m.as_str()).parse::<i32>().unwrap_or(-1);
-                let post_data = req.body();
-                if let Ok(location_partial) = serde_json::from_str::<LocationPartial>(post_data) {
-                    if location_partial.distance.is_none() {
-                        if post_data.contains("\"distance\"") {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
-                    if location_partial.city.is_none() {
-                        if post_data.contains("\"city\"") {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
-                    if location_partial.place.is_none() {
-                        if post_data.contains("\"place\"") {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
-                    if location_partial.country.is_none() {
-                        if post_data.contains("\"country\"") {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
-                    if let Some(mut location) = load_location(id, &self.locations) {
-                        found = true;
-                        if let Some(distance) = location_partial.distance {
-                            location.distance = distance;
-                        }
-                        if let Some(ref city) = location_partial.city {
-                            location.city = city.to_string();
-                        }
-                        if let Some(ref place) = location_partial.place {
-                            location.place = place.to_string();
-                        }
-                        if let Some(ref country) = location_partial.country {
-                            location.country = country.to_string();
-                        }
+    /// Email must not be used by another user
+    fn reserve_email(&self, user: &User) -> Result<(), ApiError> {
+        if !self.emails.reserve(user.email.clone(), user.id) {
+            return Err(ApiError::conflict("email", "email already used"));
+        }
+        Ok(())
+    }
 
-                        // update user visits
-                        if let Some(location_mark_list) = load_location_mark_list(location.id, &self.location_marks) {
-                            let users = location_mark_list.marks.iter().map(|i| i.user).collect::<HashSet<_>>();
-                            for user_id in users.iter() {
-                                if let Some(mut user_visits) = load_user_visits(*user_id, &self.user_visits) {
-                                    let mut changed = false;
-                                    for item in user_visits.visits.iter_mut() {
-                                        if item.location == location.id {
-                                            changed = true;
-                                            item.distance = location.distance;
-                                            item.country = location.country.to_owned();
-                                            item.body.place = location.place.to_owned();
-                                        }
-                                    }
-                                    if changed {
-                                        save_user_visits(user_visits, &self.user_visits);
-                                    }
-                                };
-                            }
-                        }
+    /// Users matching the `USER_FILTERS` ordered by id, the most selective indexed field picks
+    /// the candidates, pages are `SEARCH_LIMIT` long by default
+    fn users_content(&self, query: Option<&str>) -> Result<String, ApiError> {
+        self.check_query(query.unwrap_or(""), &USERS_PARAMS)?;
+        let query_string = query.unwrap_or("");
+        let page = query::parse_page(query_string)?.bounded(SEARCH_LIMIT, MAX_SEARCH_LIMIT);
+        let filters = self.parse_filters(&USER_FILTERS, query_string)?;
+
+        let born = filter::int_range(&filters, "birth_date");
+        let ids = if let Some(emails) = filter::equal_values(&filters, "email") {
+            let mut ids: Vec<i32> = emails.iter()
+                .filter_map(Value::as_str)
+                .filter_map(|email| self.emails.get(&email.to_string()))
+                .collect();
+            ids.sort();
+            ids.dedup();
+            ids
+        } else if let Some(last_names) = filter::equal_values(&filters, "last_name") {
+            self.user_last_names.get_any(last_names.iter().filter_map(Value::as_str))
+        } else if born != (None, None) {
+            self.user_birth_dates.between(born.0, born.1)
+        } else {
+            self.users.read().keys().cloned().collect::<Vec<i32>>()
+        };
+        let ids = query::ids_after(&ids, &page);
+
+        let users = self.users.read();
+        let matched = |id: &i32| users.get(id).filter(|u| filter::matches_all(&filters, *u));
+        let cursor = |u: &&User| Cursor(u.id as i64, u.id);
+        let (data, next) = match page.order {
+            Order::Asc => query::take_page(ids.iter().filter_map(matched), &page, cursor),
+            Order::Desc => query::take_page(ids.iter().rev().filter_map(matched), &page, cursor),
+        };
+
+        let users = data.into_iter().cloned().collect();
+        let next = next.map(|c| c.to_string());
+        Ok(serde_json::to_string(&UsersPage { users, next }).unwrap_or_default())
+    }
 
-                        save_location(location, &self.locations);
+    /// Locations matching the `LOCATION_FILTERS` ordered by id, country and city are looked up
+    /// in the indexes, pages are `SEARCH_LIMIT` long by default
+    fn locations_content(&self, query: Option<&str>) -> Result<String, ApiError> {
+        self.check_query(query.unwrap_or(""), &LOCATIONS_PARAMS)?;
+        let query_string = query.unwrap_or("");
+        let page = query::parse_page(query_string)?.bounded(SEARCH_LIMIT, MAX_SEARCH_LIMIT);
+        let filters = self.parse_filters(&LOCATION_FILTERS, query_string)?;
+
+        let ids = self.place_ids(&filters)
+            .unwrap_or_else(|| self.locations.read().keys().cloned().collect());
+        let ids = query::ids_after(&ids, &page);
+
+        let locations = self.locations.read();
+        let matched = |id: &i32| locations.get(id).filter(|l| filter::matches_all(&filters, *l));
+        let cursor = |l: &&Location| Cursor(l.id as i64, l.id);
+        let (data, next) = match page.order {
+            Order::Asc => query::take_page(ids.iter().filter_map(matched), &page, cursor),
+            Order::Desc => query::take_page(ids.iter().rev().filter_map(matched), &page, cursor),
+        };
+
+        let locations = data.into_iter().cloned().collect();
+        let next = next.map(|c| c.to_string());
+        Ok(serde_json::to_string(&LocationsPage { locations, next }).unwrap_or_default())
+    }
 
-                        resp.body(2, "{}");
-                    }
-                } else {
-                    resp.status_code(400, "Bad Request");
-                    return future::ok(resp);
-                }
-            } else {
-                let id = cap.name("id").map_or("", |m| m.as_str()).parse::<i32>().unwrap_or(-1);
-                if let Some(ref content) = content_location(id, &self.locations) {
-                    found = true;
-                    resp.body(content.len(), content);
-                }
-            }
+    fn create_user(&self, body: &str) -> Result<String, ApiError> {
+        let user = parse_entity::<User>(body, self.config.strict_body)?;
+        if self.users.exists(user.id) {
+            return Err(ApiError::conflict("id", "user already exists"));
+        }
+        self.reserve_email(&user)?;
+        let id = user.id;
+        let email = user.email.clone();
+        let (last_name, birth_date) = (user.last_name.clone(), user.birth_date);
+        if !self.users.insert_unique(user) {
+            self.emails.release(&email, id);
+            return Err(ApiError::conflict("id", "user already exists"));
         }
+        self.user_last_names.insert(last_name, id);
+        self.user_birth_dates.insert(birth_date, id);
+        // initialize user visits
+        self.user_visits.save(UserVisitList::new(id));
+        Ok("{}".to_string())
+    }
 
-        if let Some(cap) = VISITS_RE.captures(&path) {
-            if is_post {
-                let id = cap.name("id").map_or("", |m| m.as_str()).parse::<i32>().unwrap_or(-1);
-                let post_data = req.body();
-                if let Ok(visit_partial) = serde_json::from_str::<VisitPartial>(post_data) {
-                    if visit_partial.mark.is_none() {
-                        if post_data.contains("\"mark\"") {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
-                    if visit_partial.user.is_none() {
-                        if post_data.contains("\"user\"") {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
-                    if visit_partial.location.is_none() {
-                        if post_data.contains("\"location\"") {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
-                    if visit_partial.visited_at.is_none() {
-                        if post_data.contains("\"visited_at\"") {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
+    fn create_location(&self, body: &str) -> Result<String, ApiError> {
+        let location = parse_entity::<Location>(body, self.config.strict_body)?;
+        let id = location.id;
+        let (country, city) = (location.country.clone(), location.city.clone());
+        if !self.locations.insert_unique(location) {
+            return Err(ApiError::conflict("id", "location already exists"));
+        }
+        self.countries.update(country.clone(), |t| t.locations += 1);
+        self.location_countries.insert(country, id);
+        self.location_cities.insert(city, id);
+        // initialize location marks
+        self.location_marks.save(LocationMarkList::new(id));
+        Ok("{}".to_string())
+    }
 
-                    // validate FK
-                    if let Some(user) = visit_partial.user {
-                        if !exists_user(user, &self.users) {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
-                    if let Some(location) = visit_partial.location {
-                        if !exists_location(location, &self.locations) {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                    }
+    /// Referenced user and location of the visit, both are read under the locks taken together
+    fn references(&self, visit: &Visit) -> Result<(User, Location), ApiError> {
+        let users = self.users.read();
+        let locations = self.locations.read();
+        let user = users.get(&visit.user)
+            .ok_or_else(|| ApiError::unprocessable("user", &format!("user {} does not exist", visit.user)))?;
+        let location = locations.get(&visit.location)
+            .ok_or_else(|| ApiError::unprocessable("location", &format!("location {} does not exist", visit.location)))?;
+        Ok((user.clone(), location.clone()))
+    }
 
-                    if let Some(mut visit) = load_visit(id, &self.visits) {
-                        found = true;
-                        let mut visited_at_changed = false;
-                        let mut old_user: i32 = 0;
-                        let mut old_location: i32 = 0;
-                        if let Some(mark) = visit_partial.mark {
-                            visit.mark = mark;
-                        }
-                        if let Some(user) = visit_partial.user {
-                            if visit.user != user {
-                                old_user = visit.user;
-                                visit.user = user;
-                            }
-                        }
-                        if let Some(location) = visit_partial.location {
-                            if visit.location != location {
-                                old_location = visit.location;
-                                visit.location = location;
-                            }
-                        }
-                        if let Some(visited_at) = visit_partial.visited_at {
-                            visited_at_changed = visit.visited_at != visited_at;
-                            visit.visited_at = visited_at;
-                        }
+    fn create_visit(&self, body: &str) -> Result<String, ApiError> {
+        let visit = parse_entity::<Visit>(body, self.config.strict_body)?;
+        let (user, location) = self.references(&visit)?;
+        if !self.visits.insert_unique(visit.clone()) {
+            return Err(ApiError::conflict("id", "visit already exists"));
+        }
+        self.index_visit(&visit, &user, &location);
+        Ok("{}".to_string())
+    }
 
-                        // if user and location was not changed
-                        if old_user == 0 && old_location == 0 {
-                            // update user marks
-                            if let Some(mut location_mark_list) = load_location_mark_list(visit.location, &self.location_marks) {
-                                let mut changed = false;
-                                for item in location_mark_list.marks.iter_mut() {
-                                    if item.visit == visit.id {
-                                        changed = true;
-                                        item.visited_at = visit.visited_at;
-                                        item.mark = visit.mark;
-                                    }
-                                }
-                                if changed {
-                                    save_location_mark_list(location_mark_list, &self.location_marks);
-                                }
-                            };
-
-                            // update user visits
-                            if let Some(mut user_visits) = load_user_visits(visit.user, &self.user_visits) {
-                                let mut changed = false;
-                                for item in user_visits.visits.iter_mut() {
-                                    if item.visit == visit.id {
-                                        changed = true;
-                                        item.body.visited_at = visit.visited_at;
-                                        item.body.mark = visit.mark;
-                                    }
-                                }
-                                if changed {
-                                    if visited_at_changed {
-                                        user_visits.visits.sort_by(|a, b| a.body.visited_at.cmp(&b.body.visited_at));
-                                    }
-                                    save_user_visits(user_visits, &self.user_visits);
-                                }
-                            };
+    fn update_user(&self, id: i32, body: &str) -> Result<String, ApiError> {
+        let partial = parse_partial::<User>(body, self.config.strict_body)?;
+        let merged = self.users.merge(id, partial)?;
+        let email_changed = merged.before.email != merged.after.email;
+        if email_changed {
+            self.reserve_email(&merged.after)?;
+        }
+        if merged.before.last_name != merged.after.last_name {
+            self.user_last_names.remove(&merged.before.last_name, id);
+            self.user_last_names.insert(merged.after.last_name.clone(), id);
+        }
+        if merged.before.birth_date != merged.after.birth_date {
+            self.user_birth_dates.remove(&merged.before.birth_date, id);
+            self.user_birth_dates.insert(merged.after.birth_date, id);
+        }
+        let user = merged.after;
+
+        // update user marks
+        if let Some(user_visits) = self.user_visits.load(user.id) {
+            let locations = user_visits.visits.iter().map(|i| i.location).collect::<HashSet<_>>();
+            for location_id in locations.iter() {
+                self.location_marks.update(*location_id, |location_mark_list| {
+                    for item in location_mark_list.marks.iter_mut() {
+                        if item.user == user.id {
+                            item.gender = user.gender;
+                            item.birth_date = user.birth_date;
                         }
+                    }
+                });
+            }
+        }
 
-                        // user was changed
-                        if old_user > 0 {
-                            // remove old visit from user put it to another
-                            if let Some(mut old_user_visits) = load_user_visits(old_user, &self.user_visits) {
-                                // find old visit
-                                if let Some(index) = old_user_visits.visits.iter().position(|i| i.visit == id) {
-                                    // remove old visit
-                                    old_user_visits.visits.remove(index);
-                                    old_user_visits.visits.sort_by(|a, b| a.body.visited_at.cmp(&b.body.visited_at));
-                                    save_user_visits(old_user_visits, &self.user_visits);
-
-                                    if let Some(new_location) = load_location(visit.location, &self.locations) {
-                                        // construct body
-                                        let user_visit_body = UserVisitBody {
-                                            visited_at: visit.visited_at,
-                                            mark: visit.mark,
-                                            place: new_location.place.to_owned(),
-                                        };
-
-                                        let new_visit = UserVisit {
-                                            user: visit.user,
-                                            visit: visit.id,
-                                            location: visit.location,
-                                            distance: new_location.distance,
-                                            country: new_location.country.to_owned(),
-                                            body: user_visit_body,
-                                        };
-
-                                        // insert new user visit
-                                        if let Some(mut new_user_visits) = load_user_visits(visit.user, &self.user_visits) {
-                                            new_user_visits.visits.push(new_visit);
-                                            new_user_visits.visits.sort_by(|a, b| a.body.visited_at.cmp(&b.body.visited_at));
-                                            save_user_visits(new_user_visits, &self.user_visits);
-                                        }
-                                    }
-                                }
-                            }
-                        }
+        self.users.save(user);
+        if email_changed {
+            self.emails.release(&merged.before.email, id);
+        }
+        Ok("{}".to_string())
+    }
 
-                        // location was changed
-                        if old_location > 0 {
-                            // remove old visit from user put it to another
-                            if let Some(mut old_location_marks) = load_location_mark_list(old_location, &self.location_marks) {
-                                // find old visit
-                                if let Some(index) = old_location_marks.marks.iter().position(|i| i.visit == id) {
-                                    // remove old visit
-                                    old_location_marks.marks.remove(index);
-                                    save_location_mark_list(old_location_marks, &self.location_marks);
-
-                                    // insert new location mark for new location
-                                    if let Some(new_user) = load_user(visit.user, &self.users) {
-                                        if let Some(mut new_location_marks) = load_location_mark_list(visit.location, &self.location_marks) {
-                                            new_location_marks.marks.push(LocationMark {
-                                                user: new_user.id,
-                                                visit: visit.id,
-                                                gender: match new_user.gender.as_ref() {
-                                                    "m" => Gender::MALE,
-                                                    _ => Gender::FEMALE,
-                                                },
-                                                birth_date: new_user.birth_date,
-                                                mark: visit.mark,
-                                                visited_at: visit.visited_at,
-                                            });
-                                            save_location_mark_list(new_location_marks, &self.location_marks);
-                                        }
-                                    }
-                                }
-                            }
+    fn update_location(&self, id: i32, body: &str) -> Result<String, ApiError> {
+        let partial = parse_partial::<Location>(body, self.config.strict_body)?;
+        let merged = self.locations.merge(id, partial)?;
+        if merged.before.country != merged.after.country {
+            self.location_countries.remove(&merged.before.country, id);
+            self.location_countries.insert(merged.after.country.clone(), id);
+
+            // move location totals to the new country while its marks are locked
+            self.location_marks.update(id, |marks| {
+                let (sum, count) = (marks.mark_sum, marks.marks.len() as i64);
+                self.countries.update(merged.before.country.clone(), |t| {
+                    t.locations -= 1;
+                    t.mark_sum -= sum;
+                    t.visits -= count;
+                });
+                self.countries.update(merged.after.country.clone(), |t| {
+                    t.locations += 1;
+                    t.mark_sum += sum;
+                    t.visits += count;
+                });
+            });
+        }
+        if merged.before.city != merged.after.city {
+            self.location_cities.remove(&merged.before.city, id);
+            self.location_cities.insert(merged.after.city.clone(), id);
+        }
+        let location = merged.after;
+
+        // update user visits
+        if let Some(location_mark_list) = self.location_marks.load(location.id) {
+            let users = location_mark_list.marks.iter().map(|i| i.user).collect::<HashSet<_>>();
+            for user_id in users.iter() {
+                self.user_visits.update(*user_id, |user_visits| {
+                    for item in user_visits.visits.iter_mut() {
+                        if item.location == location.id {
+                            item.distance = location.distance;
+                            item.country = location.country.clone();
+                            item.city = location.city.clone();
+                            item.body.place = location.place.clone();
                         }
-
-                        save_visit(visit, &self.visits);
-                        resp.body(2, "{}");
                     }
-                } else {
-                    resp.status_code(400, "Bad Request");
-                    return future::ok(resp);
-                }
-            } else {
-                let id = cap.name("id").map_or("", |m| m.as_str()).parse::<i32>().unwrap_or(-1);
-                if let Some(content) = content_visit(id, &self.visits) {
-                    found = true;
-                    resp.body(content.len(), &content);
-                }
+                });
             }
         }
 
-        if let Some(cap) = USER_VISITS_RE.captures(&path) {
-            let id = cap.name("id").map_or("", |m| m.as_str()).parse::<i32>().unwrap_or(-1);
-            if let Some(visits) = load_user_visits(id, &self.user_visits) {
-                found = true;
-
-                // parse filters
-                if let Some(query_string) = path.split("?").nth(1) {
+        self.locations.save(location);
+        Ok("{}".to_string())
+    }
 
-                    let mut invalid_query_param = false;
+    fn update_visit(&self, id: i32, body: &str) -> Result<String, ApiError> {
+        let partial = parse_partial::<Visit>(body, self.config.strict_body)?;
+        let merged = self.visits.merge(id, partial)?;
+        let (user, location) = self.references(&merged.after)?;
 
-                    let params = query_string.split("&").map(|p| {
-                        if invalid_query_param {
-                            return QueryFilter {
-                                key: QueryField::Other,
-                                value_i32: None,
-                                value_i64: None,
-                                value_str: None,
-                                value_gender: None,
-                                value_dt: None,
-                            };
-                        }
-                        let mut parts = p.split('=');
-                        let key = parts.nth(0).unwrap_or("");
-
-                        match key {
-                            "fromDate" => QueryFilter {
-                                key: QueryField::FromDate,
-                                value_i32: None,
-                                value_i64: match parts.nth(0).unwrap_or("").parse::<i64>() {
-                                    Ok(value) => Some(value),
-                                    Err(_) => {
-                                        invalid_query_param = true;
-                                        None
-                                    }
-                                },
-                                value_str: None,
-                                value_gender: None,
-                                value_dt: None,
-                            },
-                            "toDate" => QueryFilter {
-                                key: QueryField::ToDate,
-                                value_i32: None,
-                                value_i64: match parts.nth(0).unwrap_or("").parse::<i64>() {
-                                    Ok(value) => Some(value),
-                                    Err(_) => {
-                                        invalid_query_param = true;
-                                        None
-                                    }
-                                },
-                                value_str: None,
-                                value_gender: None,
-                                value_dt: None,
-                            },
-                            "toDistance" => QueryFilter {
-                                key: QueryField::ToDistance,
-                                value_i32: match parts.nth(0).unwrap_or("").parse::<i32>() {
-                                    Ok(value) => Some(value),
-                                    Err(_) => {
-                                        invalid_query_param = true;
-                                        None
-                                    }
-                                },
-                                value_i64: None,
-                                value_str: None,
-                                value_gender: None,
-                                value_dt: None,
-                            },
-                            "country" => QueryFilter {
-                                key: QueryField::Country,
-                                value_i32: None,
-                                value_i64: None,
-                                value_str: match parts.nth(0) {
-                                    Some(value) => match urlencoding::decode(value) {
-                                        Ok(decoded_value) => Some(decoded_value),
-                                        Err(_) => {
-                                            invalid_query_param = true;
-                                            None
-                                        },
-                                    },
-                                    None => None,
-                                },
-                                value_gender: None,
-                                value_dt: None,
-                            },
-                            _ => QueryFilter {
-                                key: QueryField::Other,
-                                value_i32: None,
-                                value_i64: None,
-                                value_str: None,
-                                value_gender: None,
-                                value_dt: None,
-                            }
-                        }
-                    }).collect::<Vec<QueryFilter>>();
+        self.unindex_visit(&merged.before);
+        self.index_visit(&merged.after, &user, &location);
 
-                    if invalid_query_param {
-                        resp.status_code(400, "Bad Request");
-                        return future::ok(resp);
-                    }
+        self.visits.save(merged.after);
+        Ok("{}".to_string())
+    }
 
-                    let data = visits.visits.iter().
-                        cloned().
-                        filter(|v| {
-                            for param in &params {
-                                match param.key {
-                                    QueryField::FromDate => {
-                                        if let Some(value_i64) = param.value_i64 {
-                                            if v.body.visited_at <= value_i64  {
-                                                return false;
-                                            }
-                                        }
-                                    },
-                                    QueryField::ToDate => {
-                                        if let Some(value_i64) = param.value_i64 {
-                                            if v.body.visited_at >= value_i64 {
-                                                return false;
-                                            }
-                                        }
-                                    },
-                                    QueryField::ToDistance => {
-                                        if let Some(value_i32) = param.value_i32 {
-                                            if v.distance >= value_i32 {
-                                                return false;
-                                            }
-                                        }
-                                    },
-                                    QueryField::Country => {
-                                        if let Some(ref value_str) = param.value_str {
-                                            if &v.country != value_str {
-                                                return false;
-                                            }
-                                        }
-                                    },
-                                    _ => (),
-                                };
-                            }
-                            true
-                        }).
-                        map(|v| v.body).collect::<Vec<UserVisitBody>>();
-
-                    let payload = UserVisitResponse {
-                        visits: data,
-                    };
-                    if let Ok(response) = serde_json::to_string(&payload) {
-                        resp.body(response.len(), &response);
-                    }
-                } else {
-                    let data = visits.visits.iter().cloned().map(|v| v.body).collect::<Vec<UserVisitBody>>();
-                    let payload = UserVisitResponse {
-                        visits: data,
-                    };
-                    if let Ok(response) = serde_json::to_string(&payload) {
-                        resp.body(response.len(), &response);
-                    }
-                }
-            }
+    fn user_visits_content(&self, id: i32, query: Option<&str>) -> Result<String, ApiError> {
+        self.check_query(query.unwrap_or(""), &USER_VISITS_PARAMS)?;
+        let visits = self.user_visits.load(id).ok_or(ApiError::NotFound)?;
+        let query_string = query.unwrap_or("");
+        let page = query::parse_page(query_string)?;
+        let fields = parse_user_visit_fields(query_string)?;
+
+        let params = self.parse_filters(&USER_VISIT_FILTERS, query_string)?;
+
+        let range = visits.range(&page);
+        let matched = range.iter().filter(|v| filter::matches_all(&params, *v));
+        let (data, next) = match page.order {
+            Order::Asc => query::take_page(matched, &page, |v| v.cursor()),
+            Order::Desc => query::take_page(matched.rev(), &page, |v| v.cursor()),
+        };
+
+        let next = next.map(|c| c.to_string());
+        match fields {
+            Some(fields) => {
+                let visits = data.into_iter().map(|visit| SelectedFields { fields: &fields, visit }).collect::<Vec<SelectedFields>>();
+                Ok(serde_json::to_string(&VisitsResponse { visits, next }).unwrap_or_default())
+            },
+            None => {
+                let visits = data.into_iter().map(|v| v.body.clone()).collect::<Vec<UserVisitBody>>();
+                Ok(serde_json::to_string(&VisitsResponse { visits, next }).unwrap_or_default())
+            },
         }
+    }
 
-        if let Some(cap) = LOCATION_MARKS_RE.captures(&path) {
-            let id = cap.name("id").map_or("", |m| m.as_str()).parse::<i32>().unwrap_or(-1);
-            if let Some(marks) = load_location_mark_list(id, &self.location_marks) {
-                found = true;
+    fn user_stats_content(&self, id: i32, query: Option<&str>) -> Result<String, ApiError> {
+        self.check_query(query.unwrap_or(""), &USER_STATS_PARAMS)?;
+        let visits = self.user_visits.load(id).ok_or(ApiError::NotFound)?;
+        let params = self.parse_filters(&USER_VISIT_FILTERS, query.unwrap_or(""))?;
 
-                // parse filters
-                if let Some(query_string) = path.split("?").nth(1) {
+        let selected = visits.visits.iter().filter(|v| filter::matches_all(&params, *v)).collect::<Vec<&UserVisit>>();
+        Ok(decimal::to_json(&UserStats::new(&selected, &self.config.avg_precision)))
+    }
 
-                    let mut invalid_query_param = false;
+    fn location_avg_content(&self, id: i32, query: Option<&str>) -> Result<String, ApiError> {
+        self.check_query(query.unwrap_or(""), &LOCATION_MARKS_PARAMS)?;
+        let marks = self.location_marks.load(id).ok_or(ApiError::NotFound)?;
+        let params = self.parse_filters(&LOCATION_MARK_FILTERS, query.unwrap_or(""))?;
 
-                    let params = query_string.split("&").map(|p| {
-                        if invalid_query_param {
-                            return QueryFilter {
-                                key: QueryField::Other,
-                                value_i32: None,
-                                value_i64: None,
-                                value_str: None,
-                                value_gender: None,
-                                value_dt: None,
-                            };
-                        }
-                        let mut parts = p.split('=');
-                        let key = parts.nth(0).unwrap_or("");
-                        let now = Utc::now();
-
-                        match key {
-                            "fromDate" => QueryFilter {
-                                key: QueryField::FromDate,
-                                value_i32: None,
-                                value_i64: match parts.nth(0).unwrap_or("").parse::<i64>() {
-                                    Ok(value) => Some(value),
-                                    Err(_) => {
-                                        invalid_query_param = true;
-                                        None
-                                    }
-                                },
-                                value_str: None,
-                                value_gender: None,
-                                value_dt: None,
-                            },
-                            "toDate" => QueryFilter {
-                                key: QueryField::ToDate,
-                                value_i32: None,
-                                value_i64: match parts.nth(0).unwrap_or("").parse::<i64>() {
-                                    Ok(value) => Some(value),
-                                    Err(_) => {
-                                        invalid_query_param = true;
-                                        None
-                                    }
-                                },
-                                value_str: None,
-                                value_gender: None,
-                                value_dt: None,
-                            },
-                            "fromAge" => QueryFilter {
-                                key: QueryField::FromAge,
-                                value_i32: None,
-                                value_i64: match parts.nth(0).unwrap_or("").parse::<i32>() {
-                                    Ok(value) => if value >= 0 {
-                                        if let Some(dt) = now.with_year(now.year() - value) {
-                                            Some(dt.timestamp())
-                                        } else {
-                                            invalid_query_param = true;
-                                            None
-                                        }
-                                    } else {
-                                        invalid_query_param = true;
-                                        None
-                                    },
-                                    Err(_) => {
-                                        invalid_query_param = true;
-                                        None
-                                    }
-                                },
-                                value_str: None,
-                                value_gender: None,
-                                value_dt: None,
-                            },
-                            "toAge" => QueryFilter {
-                                key: QueryField::ToAge,
-                                value_i32: None,
-                                value_i64: match parts.nth(0).unwrap_or("").parse::<i32>() {
-                                    Ok(value) => if value >= 0 {
-                                        if let Some(dt) = now.with_year(now.year() - value) {
-                                            Some(dt.timestamp())
-                                        } else {
-                                            invalid_query_param = true;
-                                            None
-                                        }
-                                    } else {
-                                        invalid_query_param = true;
-                                        None
-                                    },
-                                    Err(_) => {
-                                        invalid_query_param = true;
-                                        None
-                                    }
-                                },
-                                value_str: None,
-                                value_gender: None,
-                                value_dt: None,
-                            },
-                            "gender" => QueryFilter {
-                                key: QueryField::Gender,
-                                value_i32: None,
-                                value_i64: None,
-                                value_str: None,
-                                value_gender: match parts.nth(0).unwrap_or("") {
-                                    "m" => Some(Gender::MALE),
-                                    "f" => Some(Gender::FEMALE),
-                                    _ => {
-                                        invalid_query_param = true;
-                                        None
-                                    }
-                                },
-                                value_dt: None,
-                            },
-                            _ => QueryFilter {
-                                key: QueryField::Other,
-                                value_i32: None,
-                                value_i64: None,
-                                value_str: None,
-                                value_gender: None,
-                                value_dt: None,
-                            }
-                        }
-                    }).collect::<Vec<QueryFilter>>();
+        let sum = SumCount { sum: 0, count: 0};
+        let result = marks.marks.iter().
+            filter(|v| filter::matches_all(&params, *v)).
+            fold(sum, |mut s, val| { s.sum += val.mark as i64; s.count += 1; s });
 
-                    if invalid_query_param {
-                        resp.status_code(400, "Bad Request");
-                        return future::ok(resp);
-                    }
+        Ok(self.avg_content(&result))
+    }
 
-                    let sum = SumCount { sum: 0, count: 0};
-                    let result = &marks.marks.iter().
-                        filter(|v| {
-                            for param in &params {
-                                match param.key {
-                                    QueryField::FromDate => {
-                                        if let Some(value_i64) = param.value_i64 {
-                                            if v.visited_at <= value_i64  {
-                                                return false;
-                                            }
-                                        }
-                                    },
-                                    QueryField::ToDate => {
-                                        if let Some(value_i64) = param.value_i64 {
-                                            if v.visited_at >= value_i64 {
-                                                return false;
-                                            }
-                                        }
-                                    },
-                                    QueryField::FromAge => {
-                                        if let Some(value_i64) = param.value_i64 {
-                                            if v.birth_date >= value_i64 {
-                                                return false;
-                                            }
-                                        }
-                                    },
-                                    QueryField::ToAge => {
-                                        if let Some(value_i64) = param.value_i64 {
-                                            if v.birth_date <= value_i64  {
-                                                return false;
-                                            }
-                                        }
-                                    },
-                                    QueryField::Gender => {
-                                        if let Some(ref value_gender) = param.value_gender {
-                                            if &v.gender != value_gender {
-                                                return false;
-                                            }
-                                        }
-                                    },
-                                    _ => (),
-                                };
-                            }
-                            true
-                        }).
-                        fold(sum, |mut s, val| { s.sum += val.mark as i32; s.count += 1; s });
-
-                    if result.count > 0 {
-                        let avg = format!("{:.5}", result.sum as f32 / result.count as f32);
-                        let mut avg = avg.trim_right_matches("0").to_string();
-                        if avg.ends_with(".") {
-                            avg = format!("{}0", avg);
-                        }
-                        let response = format!("{{\"avg\":{}}}", avg);
-                        resp.body(response.len(), &response);
-                    } else {
-                        let response = "{\"avg\":0}";
-                        resp.body(response.len(), &response);
-                    }
-                } else {
-                    let sum = SumCount { sum: 0, count: 0};
-                    let result = &marks.marks.iter().fold(sum, |mut s, val| { s.sum += val.mark as i32; s.count += 1; s });
-                    if result.count > 0 {
-                        let avg = format!("{:.5}", result.sum as f32 / result.count as f32);
-                        let mut avg = avg.trim_right_matches("0").to_string();
-                        if avg.ends_with(".") {
-                            avg = format!("{}0", avg);
-                        }
-                        let response = format!("{{\"avg\":{}}}", avg);
-                        resp.body(response.len(), &response);
-                    } else {
-                        let response = "{\"avg\":0}";
-                        resp.body(response.len(), &response);
-                    }
-                }
-            }
-        }
+    fn countries_content(&self, query: Option<&str>) -> Result<String, ApiError> {
+        self.check_query(query.unwrap_or(""), &NO_PARAMS)?;
+        let mut countries = self.countries.entries().into_iter()
+            .filter(|(_, t)| t.locations > 0)
+            .map(|(country, t)| {
+                let avg = decimal::round_ratio(t.mark_sum, t.visits, &self.config.avg_precision);
+                CountrySummary { country, locations: t.locations, visits: t.visits, avg }
+            })
+            .collect::<Vec<CountrySummary>>();
+        countries.sort_by(|a, b| a.country.cmp(&b.country));
+        Ok(decimal::to_json(&CountriesResponse { countries }))
+    }
 
-        if is_post && !found {
-            let data = req.body();
-            if let Some(_) = USER_NEW_RE.captures(path) {
-                match serde_json::from_str::<User>(data) {
-                    Ok(user) => {
-                        found = true;
-                        if exists_user(user.id, &self.users) {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                        // initialize user visits
-                        let user_visits = UserVisitList {
-                            user: user.id,
-                            visits: Vec::new(),
-                        };
-                        save_user_visits(user_visits, &self.user_visits);
-                        save_user(user, &self.users);
-                        resp.body(2, "{}");
-                    },
-                    Err(_) => {
-                        resp.status_code(400, "Bad Request");
-                        return future::ok(resp);
-                    }
-                };
+    /// Average mark of the country locations, unfiltered one is read from the country totals
+    fn country_avg_content(&self, name: &str, query: Option<&str>) -> Result<String, ApiError> {
+        self.check_query(query.unwrap_or(""), &LOCATION_MARKS_PARAMS)?;
+        let country = urlencoding::decode(name).map_err(|_| ApiError::NotFound)?;
+        let params = self.parse_filters(&LOCATION_MARK_FILTERS, query.unwrap_or(""))?;
+        let filtered = !params.is_empty();
+
+        let result = if filtered {
+            let ids = self.location_countries.get(country.as_str());
+            if ids.is_empty() {
+                return Err(ApiError::NotFound);
             }
-            if let Some(_) = LOCATION_NEW_RE.captures(path) {
-                match serde_json::from_str::<Location>(data) {
-                    Ok(location) => {
-                        found = true;
-                        // println!("New location: {:?}", location);
-                        if exists_location(location.id, &self.locations) {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
-                        // initialize location marks
-                        let location_mark_list = LocationMarkList {
-                            location: location.id,
-                            marks: Vec::new(),
-                        };
-                        save_location_mark_list(location_mark_list, &self.location_marks);
-                        save_location(location, &self.locations);
-                        resp.body(2, "{}");
-                    },
-                    Err(_) => {
-                        resp.status_code(400, "Bad Request");
-                        return future::ok(resp);
-                    }
-                };
-            }
-            if let Some(_) = VISITS_NEW_RE.captures(path) {
-                match serde_json::from_str::<Visit>(data) {
-                    Ok(visit) => {
-                        found = true;
-                        if exists_visit(visit.id, &self.visits) {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
+            ids.into_iter().filter_map(|id| self.location_marks.load(id)).fold(SumCount { sum: 0, count: 0 }, |s, marks| {
+                marks.marks.iter().
+                    filter(|v| filter::matches_all(&params, *v)).
+                    fold(s, |mut s, val| { s.sum += val.mark as i64; s.count += 1; s })
+            })
+        } else {
+            let tally = self.countries.get(country.as_str()).ok_or(ApiError::NotFound)?;
+            SumCount { sum: tally.mark_sum, count: tally.visits }
+        };
+
+        Ok(self.avg_content(&result))
+    }
 
-                        // process user visits
-                        if let Some(mut visits) = load_user_visits(visit.user, &self.user_visits) {
-                            if let Some(location) = load_location(visit.location, &self.locations) {
-                                // construct body
-                                let user_visit_body = UserVisitBody {
-                                    visited_at: visit.visited_at,
-                                    mark: visit.mark,
-                                    place: location.place.to_owned(),
-                                };
-
-                                // construct index
-                                let user_visit = UserVisit {
-                                    user: visit.user,
-                                    visit: visit.id,
-                                    location: location.id,
-                                    distance: location.distance,
-                                    country: location.country.to_owned(),
-                                    body: user_visit_body,
-                                };
-
-                                visits.visits.push(user_visit);
-                                visits.visits.sort_by(|a, b| a.body.visited_at.cmp(&b.body.visited_at));
-
-                                save_user_visits(visits, &self.user_visits);
-
-                                // process location marks
-                                if let Some(mut marks) = load_location_mark_list(visit.location, &self.location_marks) {
-                                    if let Some(user) = load_user(visit.user, &self.users) {
-                                        marks.marks.push(LocationMark {
-                                            user: user.id,
-                                            visit: visit.id,
-                                            gender: match user.gender.as_ref() {
-                                                "m" => Gender::MALE,
-                                                _ => Gender::FEMALE,
-                                            },
-                                            birth_date: user.birth_date,
-                                            // dt: {
-                                            //     let ts = NaiveDateTime::from_timestamp(user.birth_date, 0);
-                                            //     DateTime::<Utc>::from_utc(ts, Utc)
-                                            // },
-                                            mark: visit.mark,
-                                            visited_at: visit.visited_at,
-                                        });
-
-                                        save_location_mark_list(marks, &self.location_marks);
-                                    }
-                                }
+    fn location_visits_content(&self, id: i32, query: Option<&str>) -> Result<String, ApiError> {
+        self.check_query(query.unwrap_or(""), &LOCATION_VISITS_PARAMS)?;
+        let marks = self.location_marks.load(id).ok_or(ApiError::NotFound)?;
+        let query_string = query.unwrap_or("");
+        let page = query::parse_page(query_string)?;
+        let params = self.parse_filters(&LOCATION_MARK_FILTERS, query_string)?;
+
+        let range = marks.range(&page);
+        let matched = range.iter().filter(|v| filter::matches_all(&params, *v));
+        let (data, next) = match page.order {
+            Order::Asc => query::take_page(matched, &page, |v| v.cursor()),
+            Order::Desc => query::take_page(matched.rev(), &page, |v| v.cursor()),
+        };
+
+        let visits = data.into_iter().map(|v| {
+            LocationVisit { visit: v.visit, user: v.user, mark: v.mark, visited_at: v.visited_at }
+        }).collect::<Vec<LocationVisit>>();
+        let next = next.map(|c| c.to_string());
+        Ok(serde_json::to_string(&VisitsResponse { visits, next }).unwrap_or_default())
+    }
 
-                                save_visit(visit, &self.visits);
-                                resp.body(2, "{}");
-                            } else {
-                                resp.status_code(400, "Bad Request");
-                                return future::ok(resp);
-                            }
-                        } else {
-                            resp.status_code(400, "Bad Request");
-                            return future::ok(resp);
-                        }
+    fn location_stats_content(&self, id: i32, query: Option<&str>) -> Result<String, ApiError> {
+        self.check_query(query.unwrap_or(""), &LOCATION_MARKS_PARAMS)?;
+        let marks = self.location_marks.load(id).ok_or(ApiError::NotFound)?;
+        let params = self.parse_filters(&LOCATION_MARK_FILTERS, query.unwrap_or(""))?;
 
-                    },
-                    Err(_) => {
-                        resp.status_code(400, "Bad Request");
-                        return future::ok(resp);
-                    }
-                };
-            }
+        let selected = marks.marks.iter()
+            .filter(|v| filter::matches_all(&params, *v))
+            .map(|v| v.mark)
+            .collect();
+        Ok(decimal::to_json(&LocationStats::new(selected, &self.config.avg_precision)))
+    }
+
+    fn location_trend_content(&self, id: i32, query: Option<&str>) -> Result<String, ApiError> {
+        self.check_query(query.unwrap_or(""), &LOCATION_TREND_PARAMS)?;
+        let marks = self.location_marks.load(id).ok_or(ApiError::NotFound)?;
+        let query_string = query.unwrap_or("");
+        let bucket = query::parse_bucket(query_string)?;
+        let params = self.parse_filters(&LOCATION_MARK_FILTERS, query_string)?;
+
+        let mut sums = BTreeMap::new();
+        for v in marks.marks.iter().filter(|v| filter::matches_all(&params, *v)) {
+            let sum = sums.entry(bucket.start(v.visited_at)).or_insert(SumCount { sum: 0, count: 0 });
+            sum.sum += v.mark as i64;
+            sum.count += 1;
         }
 
-        if !found {
-            resp.status_code(404, "Not Found");
+        let buckets = sums.into_iter().map(|(start, s)| {
+            let avg = decimal::round_ratio(s.sum, s.count, &self.config.avg_precision);
+            TrendBucket { start, avg, count: s.count }
+        }).collect();
+        Ok(decimal::to_json(&TrendResponse { buckets }))
+    }
+
+    /// Ids of the locations in ascending order picked by the country and city filters,
+    /// None without either
+    fn place_ids(&self, filters: &[filter::Filter]) -> Option<Vec<i32>> {
+        let names = |field| filter::equal_values(filters, field)
+            .map(|values| values.iter().filter_map(Value::as_str).collect::<Vec<&str>>());
+        match (names("country"), names("city")) {
+            (Some(countries), Some(cities)) => {
+                let in_city = self.location_cities.get_any(cities);
+                Some(self.location_countries.get_any(countries).into_iter()
+                    .filter(|id| in_city.binary_search(id).is_ok())
+                    .collect())
+            },
+            (Some(countries), None) => Some(self.location_countries.get_any(countries)),
+            (None, Some(cities)) => Some(self.location_cities.get_any(cities)),
+            (None, None) => None,
         }
+    }
 
-        future::ok(resp)
+    /// Highest rated locations, unfiltered ranking walks the maintained one
+    /// and only filtered one scans the marks
+    fn top_locations_content(&self, query: Option<&str>) -> Result<String, ApiError> {
+        self.check_query(query.unwrap_or(""), &TOP_LOCATIONS_PARAMS)?;
+        let query_string = query.unwrap_or("");
+        let mut limit = 10;
+        let mut min_count = 1;
+        for (key, value) in query::pairs(query_string) {
+            match key {
+                "limit" => limit = query::parse_value(key, value)?,
+                "minCount" => min_count = query::parse_value::<i64>(key, value)?,
+                _ => (),
+            }
+        }
+        let (places, params): (Vec<_>, Vec<_>) = self.parse_filters(&TOP_LOCATION_FILTERS, query_string)?
+            .into_iter()
+            .partition(|f| PLACE_FIELDS.contains(&f.field));
+        let place_ids = if places.is_empty() {
+            None
+        } else {
+            let ids = self.place_ids(&places)
+                .unwrap_or_else(|| self.locations.read().keys().cloned().collect());
+            let locations = self.locations.read();
+            Some(ids.into_iter()
+                .filter(|id| locations.get(id).is_some_and(|l| filter::matches_all(&places, l)))
+                .collect::<Vec<i32>>())
+        };
+
+        let ranks = if params.is_empty() {
+            let place_ids = place_ids.map(|ids| ids.into_iter().collect::<HashSet<i32>>());
+            self.location_ranking.top(limit, |rank| {
+                rank.count >= min_count && place_ids.as_ref().is_none_or(|ids| ids.contains(&rank.id))
+            })
+        } else {
+            let rate = |marks: &LocationMarkList| {
+                let (sum, count) = marks.marks.iter()
+                    .filter(|v| filter::matches_all(&params, *v))
+                    .fold((0, 0), |(sum, count), v| (sum + v.mark as i64, count + 1));
+                Rank { id: marks.location, sum, count }
+            };
+            let mut ranks: Vec<Rank> = match place_ids {
+                Some(ids) => ids.into_iter().filter_map(|id| self.location_marks.load(id)).map(|marks| rate(&marks)).collect(),
+                None => self.location_marks.read().values().map(rate).collect(),
+            };
+            ranks.retain(|rank| rank.count > 0 && rank.count >= min_count);
+            ranks.sort();
+            ranks.truncate(limit);
+            ranks
+        };
+
+        let locations = ranks.into_iter().map(|rank| RatedLocation {
+            id: rank.id,
+            avg: decimal::round_ratio(rank.sum, rank.count, &self.config.avg_precision),
+            count: rank.count,
+        }).collect();
+        Ok(decimal::to_json(&TopLocationsResponse { locations }))
     }
 }
 
-fn main() {
-
-    let users = Arc::new(RwLock::new(BTreeMap::new()));
-    let users_clone = users.clone();
+fn capture_id(cap: &Captures) -> i32 {
+    cap.name("id").map_or("", |m| m.as_str()).parse::<i32>().unwrap_or(-1)
+}
 
-    let locations = Arc::new(RwLock::new(BTreeMap::new()));
-    let locations_clone = locations.clone();
+impl Service for Travels {
+    type Request = Request;
+    type Response = Response;
+    type Error = std::io::Error;
+    type Future = future::Ok<Response, std::io::Error>;
 
-    let visits = Arc::new(RwLock::new(BTreeMap::new()));
-    let visits_clone = visits.clone();
+    fn call(&self, req: Request) -> Self::Future {
 
-    let location_marks = Arc::new(RwLock::new(BTreeMap::new()));
-    let location_marks_clone = location_marks.clone();
+        let mut resp = Response::new();
 
-    let user_visits = Arc::new(RwLock::new(BTreeMap::new()));
-    let user_visits_clone = user_visits.clone();
+        match self.route(&req) {
+            Ok(content) => {
+                resp.body(content.len(), &content);
+            },
+            Err(err) => err.render(&mut resp, self.config.strict_errors),
+        }
 
-    fn show_memory_usage() {
-        println!(
-            "-----------------\n{}",
-           String::from_utf8_lossy(&Command::new("free").arg("-m").output().expect("failed to execute process").stdout),
-        );
+        future::ok(resp)
     }
+}
 
-    show_memory_usage();
+fn main() {
+
+    let travels = Travels {
+        users: Repository::new(),
+        locations: Repository::new(),
+        visits: Repository::new(),
+        user_visits: Repository::new(),
+        location_marks: Repository::new(),
+        emails: UniqueIndex::new(),
+        user_last_names: MultiIndex::new(),
+        user_birth_dates: OrderedIndex::new(),
+        location_countries: MultiIndex::new(),
+        location_cities: MultiIndex::new(),
+        countries: Aggregate::new(),
+        location_ranking: Ranking::new(),
+        config: Arc::new(Config::from_env()),
+    };
+    let travels_clone = travels.clone();
+
+    match travels.config.clock {
+        Clock::Fixed(timestamp) => println!("Reference time: {}", timestamp),
+        Clock::System => println!("Reference time: system clock"),
+    }
+    show_memory_usage(&travels.memory_report());
 
     // process entities
     thread::spawn(move || {
@@ -1214,10 +1702,7 @@ fn main() {
         for path in paths {
 
             let filepath = path.unwrap().path().display().to_string();
-            let users = users_clone.clone();
-            let locations = locations_clone.clone();
-            let location_marks = location_marks_clone.clone();
-            let user_visits = user_visits_clone.clone();
+            let travels = travels_clone.clone();
 
             pool.execute(move || {
                 let file = File::open(filepath.to_owned()).unwrap();
@@ -1225,23 +1710,21 @@ fn main() {
                 if USER_FILE_RE.is_match(&filepath) {
                     let users_json: Users = serde_json::from_reader(&file).unwrap();
                     for user in users_json.users {
-                        let user_visit_list = UserVisitList {
-                            user: user.id,
-                            visits: Vec::new(),
-                        };
-                        save_user_visits(user_visit_list, &user_visits);
-                        save_user(user, &users);
+                        travels.user_visits.save(UserVisitList::new(user.id));
+                        travels.emails.reserve(user.email.clone(), user.id);
+                        travels.user_last_names.insert(user.last_name.clone(), user.id);
+                        travels.user_birth_dates.insert(user.birth_date, user.id);
+                        travels.users.save(user);
                     }
                 }
                 if LOCATION_FILE_RE.is_match(&filepath) {
                     let locations_json: Locations = serde_json::from_reader(&file).unwrap();
                     for location in locations_json.locations {
-                        let location_mark_list = LocationMarkList {
-                            location: location.id,
-                            marks: Vec::new(),
-                        };
-                        save_location_mark_list(location_mark_list, &location_marks);
-                        save_location(location, &locations);
+                        travels.location_marks.save(LocationMarkList::new(location.id));
+                        travels.countries.update(location.country.clone(), |t| t.locations += 1);
+                        travels.location_countries.insert(location.country.clone(), location.id);
+                        travels.location_cities.insert(location.city.clone(), location.id);
+                        travels.locations.save(location);
                     }
                 }
             });
@@ -1249,17 +1732,14 @@ fn main() {
 
         pool.join();
 
+        let orphans = Arc::new(AtomicUsize::new(0));
         let paths = fs::read_dir(data_path).unwrap();
         for path in paths {
 
-            let user_visits = user_visits_clone.clone();
-            let location_marks = location_marks_clone.clone();
-
-            let users = users_clone.clone();
-            let locations = locations_clone.clone();
-            let visits = visits_clone.clone();
-
             let filepath = path.unwrap().path().display().to_string();
+            let travels = travels_clone.clone();
+            let orphans = orphans.clone();
+
             pool.execute(move || {
 
                 let file = File::open(filepath.to_owned()).unwrap();
@@ -1267,58 +1747,22 @@ fn main() {
                 if VISITS_FILE_RE.is_match(&filepath) {
                     let visits_json: Visits = serde_json::from_reader(&file).unwrap();
                     for item in visits_json.visits {
-
-                        // populate user visits
-                        if let Some(mut visits) = load_user_visits(item.user, &user_visits) {
-                            if let Some(location) = load_location(item.location, &locations) {
-                                // construct body
-                                let user_visit_body = UserVisitBody {
-                                    visited_at: item.visited_at,
-                                    mark: item.mark,
-                                    place: location.place.to_owned(),
-                                };
-
-                                // construct index
-                                let user_visit = UserVisit {
-                                    user: item.user,
-                                    visit: item.id,
-                                    location: location.id,
-                                    distance: location.distance,
-                                    country: location.country.to_owned(),
-                                    body: user_visit_body,
-                                };
-
-                                if let Some(index) = visits.visits.iter().position(|i| i.body.visited_at > item.visited_at) {
-                                    // find position
-                                    visits.visits.insert(index, user_visit);
-                                } else {
-                                    // add to the list
-                                    visits.visits.push(user_visit);
-                                }
-
-                                save_user_visits(visits, &user_visits);
-                            }
-                        }
-
-                        // populate location marsk
-                        if let Some(mut marks) = load_location_mark_list(item.location, &location_marks) {
-                            if let Some(user) = load_user(item.user, &users) {
-                                marks.marks.push(LocationMark {
-                                    user: user.id,
-                                    visit: item.id,
-                                    gender: match user.gender.as_ref() {
-                                        "m" => Gender::MALE,
-                                        _ => Gender::FEMALE,
+                        // populate user visits and location marks
+                        match travels.references(&item) {
+                            Ok((user, location)) => travels.index_visit(&item, &user, &location),
+                            Err(err) => {
+                                orphans.fetch_add(1, Ordering::Relaxed);
+                                match travels.config.orphan_visits {
+                                    OrphanPolicy::Keep => (),
+                                    OrphanPolicy::Skip => continue,
+                                    OrphanPolicy::Fail => {
+                                        println!("Orphan visit {} in {}: {}", item.id, filepath, err);
+                                        process::exit(1);
                                     },
-                                    birth_date: user.birth_date,
-                                    mark: item.mark,
-                                    visited_at: item.visited_at,
-                                });
-                                save_location_mark_list(marks, &location_marks);
-                            }
+                                }
+                            },
                         }
-
-                        save_visit(item, &visits);
+                        travels.visits.save(item);
                     }
                 }
             });
@@ -1327,7 +1771,8 @@ fn main() {
         pool.join();
 
         println!("Entities load done {:?}", entities_load.elapsed());
-        show_memory_usage();
+        println!("Orphan visits: {} ({:?})", orphans.load(Ordering::Relaxed), travels_clone.config.orphan_visits);
+        show_memory_usage(&travels_clone.memory_report());
     });
 
     let listen_on = env::var("LISTEN").unwrap_or("0.0.0.0:80".to_string());
@@ -1335,13 +1780,84 @@ fn main() {
     let mut srv = TcpServer::new(Http, addr);
     srv.threads(num_cpus::get());
 
-    srv.serve(move || {
-        let users = users.clone();
-        let locations = locations.clone();
-        let visits = visits.clone();
-        let user_visits = user_visits.clone();
-        let location_marks = location_marks.clone();
+    srv.serve(move || Ok(travels.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_visit(id: i32, visited_at: i64) -> UserVisit {
+        let location = Location {
+            id: 1,
+            distance: 10,
+            city: Symbol::new("Paris"),
+            place: Symbol::new("Tower"),
+            country: Symbol::new("France"),
+        };
+        UserVisit::new(&Visit { id, user: 1, location: 1, visited_at, mark: 3 }, &location)
+    }
+
+    fn visits(list: &UserVisitList) -> Vec<(i64, i32)> {
+        list.visits.iter().map(|v| (v.body.visited_at, v.visit)).collect()
+    }
 
-        Ok(Travels { users, locations, visits, user_visits, location_marks })
-    })
+    #[test]
+    fn user_visits_are_ordered_by_time_then_id() {
+        let mut list = UserVisitList::new(1);
+        for &id in [7, 3, 9, 1, 5].iter() {
+            list.insert(user_visit(id, 100));
+        }
+        list.insert(user_visit(4, 50));
+        list.insert(user_visit(2, 150));
+        assert_eq!(visits(&list), vec![(50, 4), (100, 1), (100, 3), (100, 5), (100, 7), (100, 9), (150, 2)]);
+    }
+
+    #[test]
+    fn user_visit_is_removed_by_its_old_time() {
+        let mut list = UserVisitList::new(1);
+        list.insert(user_visit(1, 100));
+        list.insert(user_visit(2, 100));
+        list.insert(user_visit(3, 200));
+
+        // visit 1 moved to 300, the list still holds it at 100
+        assert!(list.remove(300, 1).is_none());
+        assert_eq!(list.remove(100, 1).map(|v| v.visit), Some(1));
+        list.insert(user_visit(1, 300));
+        assert_eq!(visits(&list), vec![(100, 2), (200, 3), (300, 1)]);
+    }
+
+    fn mark(visit: i32, visited_at: i64, mark: i8) -> LocationMark {
+        LocationMark { visited_at, birth_date: 0, gender: Gender::FEMALE, mark, user: 1, visit }
+    }
+
+    fn marks(list: &LocationMarkList) -> Vec<(i32, i8)> {
+        list.marks.iter().map(|m| (m.visit, m.mark)).collect()
+    }
+
+    #[test]
+    fn location_marks_keep_their_sum() {
+        let mut list = LocationMarkList::new(1);
+        list.insert(mark(1, 20, 3));
+        list.insert(mark(2, 10, 5));
+        list.insert(mark(3, 30, 1));
+        assert_eq!(marks(&list), vec![(2, 5), (1, 3), (3, 1)]);
+        assert_eq!(list.mark_sum, 9);
+
+        // same visit and visited_at replaces the mark
+        list.insert(mark(1, 20, 0));
+        assert_eq!(marks(&list), vec![(2, 5), (1, 0), (3, 1)]);
+        assert_eq!(list.mark_sum, 6);
+
+        assert_eq!(list.remove(10, 2).map(|m| m.mark), Some(5));
+        assert_eq!(list.mark_sum, 1);
+        assert!(list.remove(10, 2).is_none());
+        assert!(list.remove(31, 3).is_none());
+        assert_eq!(list.mark_sum, 1);
+
+        list.remove(20, 1);
+        list.remove(30, 3);
+        assert!(list.marks.is_empty());
+        assert_eq!(list.mark_sum, 0);
+    }
 }
@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::mem::size_of;
+
+/// Resident set size of the current process
+pub fn rss_bytes() -> Option<usize> {
+    let mut content = String::new();
+    File::open("/proc/self/status").ok()?.read_to_string(&mut content).ok()?;
+    // reported as `VmRSS:    1234 kB`
+    let line = content.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes = line["VmRSS:".len()..].split_whitespace().next()?.parse::<usize>().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Approximate size of the data owned by a value outside of its inline representation
+pub trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MapUsage {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// Estimate bytes held by map entries (btree node overhead is not counted)
+pub fn map_usage<T: HeapSize>(items: &BTreeMap<i32, T>) -> MapUsage {
+    MapUsage {
+        count: items.len(),
+        bytes: items.values().map(|i| size_of::<i32>() + size_of::<T>() + i.heap_size()).sum(),
+    }
+}
+
+pub fn show_map_usage(name: &str, usage: MapUsage) {
+    println!(
+        "{}: {} items, {} bytes, {} bytes per item",
+        name,
+        usage.count,
+        usage.bytes,
+        usage.bytes.checked_div(usage.count).unwrap_or(0),
+    );
+}
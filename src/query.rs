@@ -0,0 +1,182 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime};
+use urlencoding;
+
+use error::ApiError;
+use filter::Schema;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// Position in a list ordered by a sort value and entity id, rendered as `<value>_<id>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor(pub i64, pub i32);
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}_{}", self.0, self.1)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Cursor, ()> {
+        let mut parts = value.splitn(2, '_');
+        match (parts.next().map(str::parse), parts.next().map(str::parse)) {
+            (Some(Ok(sort)), Some(Ok(id))) => Ok(Cursor(sort, id)),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Pagination parameters: `limit`, `offset`, `after` cursor and `order=asc|desc`
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub after: Option<Cursor>,
+    pub order: Order,
+}
+
+impl Page {
+    /// Page size for lists which could grow with a whole table: `default` without `limit`
+    /// and at most `max`, the rest is reached by the `next` cursor
+    pub fn bounded(mut self, default: usize, max: usize) -> Page {
+        self.limit = Some(self.limit.unwrap_or(default).min(max));
+        self
+    }
+}
+
+/// Parameters accepted by an endpoint
+pub struct Params {
+    /// Single valued, the last one wins unless the query is strict
+    pub single: &'static [&'static str],
+    /// Could be repeated, values are combined
+    pub repeated: &'static [&'static str],
+    /// Filters could be repeated, equality ones match any value and the others must all match
+    pub filters: Option<&'static Schema>,
+}
+
+/// Strict query check: unknown parameters and repeated single valued ones are rejected
+pub fn check_params(query: &str, params: &Params) -> Result<(), ApiError> {
+    let mut seen = Vec::new();
+    for (key, _) in pairs(query) {
+        if params.repeated.contains(&key) || params.filters.is_some_and(|s| s.accepts(key)) {
+            continue;
+        }
+        if !params.single.contains(&key) {
+            return Err(ApiError::malformed_field(key, "unknown parameter"));
+        }
+        if seen.contains(&key) {
+            return Err(ApiError::malformed_field(key, "repeated parameter"));
+        }
+        seen.push(key);
+    }
+    Ok(())
+}
+
+/// Split query string into decoded-on-demand `key=value` pairs
+pub fn pairs(query: &str) -> Vec<(&str, &str)> {
+    query.split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let mut parts = p.splitn(2, '=');
+            (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+        })
+        .collect()
+}
+
+pub fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, ApiError> {
+    value.parse::<T>().map_err(|_| ApiError::malformed_field(key, "invalid value"))
+}
+
+/// Percent decoded string parameter
+pub fn decode_value(key: &str, value: &str) -> Result<String, ApiError> {
+    urlencoding::decode(value).map_err(|_| ApiError::malformed_field(key, "invalid value"))
+}
+
+pub fn parse_page(query: &str) -> Result<Page, ApiError> {
+    let mut page = Page { limit: None, offset: 0, after: None, order: Order::Asc };
+    for (key, value) in pairs(query) {
+        match key {
+            "limit" => page.limit = Some(parse_value(key, value)?),
+            "offset" => page.offset = parse_value(key, value)?,
+            "after" => page.after = Some(parse_value(key, value)?),
+            "order" => page.order = match value {
+                "asc" => Order::Asc,
+                "desc" => Order::Desc,
+                _ => return Err(ApiError::malformed_field(key, "expected asc or desc")),
+            },
+            _ => (),
+        }
+    }
+    Ok(page)
+}
+
+/// Ascending entity ids following the page cursor in the page order,
+/// for lists ordered by id alone
+pub fn ids_after<'a>(ids: &'a [i32], page: &Page) -> &'a [i32] {
+    match (page.after, page.order) {
+        (Some(after), Order::Asc) => &ids[ids.partition_point(|&id| id <= after.1)..],
+        (Some(after), Order::Desc) => &ids[..ids.partition_point(|&id| id < after.1)],
+        (None, _) => ids,
+    }
+}
+
+/// Take page items from the iterator which is already positioned after the cursor,
+/// returns cursor of the last taken item if there are more items
+pub fn take_page<T, I, K>(items: I, page: &Page, cursor: K) -> (Vec<T>, Option<Cursor>)
+    where I: Iterator<Item = T>, K: Fn(&T) -> Cursor
+{
+    let mut items = items.skip(page.offset);
+    match page.limit {
+        Some(limit) => {
+            let taken = items.by_ref().take(limit).collect::<Vec<T>>();
+            let next = match (items.next(), taken.last()) {
+                (Some(_), Some(last)) => Some(cursor(last)),
+                _ => None,
+            };
+            (taken, next)
+        },
+        None => (items.collect(), None),
+    }
+}
+
+/// Calendar period grouping timestamps, `bucket=week|month|year`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bucket {
+    Week,
+    Month,
+    Year,
+}
+
+impl Bucket {
+    /// Start of the UTC period containing the timestamp, weeks start on Monday
+    pub fn start(&self, timestamp: i64) -> i64 {
+        let date = match NaiveDateTime::from_timestamp_opt(timestamp, 0) {
+            Some(dt) => dt.date(),
+            None => return timestamp,
+        };
+        let start = match *self {
+            Bucket::Week => date - Duration::days(date.weekday().num_days_from_monday() as i64),
+            Bucket::Month => NaiveDate::from_ymd(date.year(), date.month(), 1),
+            Bucket::Year => NaiveDate::from_ymd(date.year(), 1, 1),
+        };
+        start.and_hms(0, 0, 0).timestamp()
+    }
+}
+
+pub fn parse_bucket(query: &str) -> Result<Bucket, ApiError> {
+    match pairs(query).into_iter().rev().find(|&(key, _)| key == "bucket") {
+        None | Some((_, "month")) => Ok(Bucket::Month),
+        Some((_, "week")) => Ok(Bucket::Week),
+        Some((_, "year")) => Ok(Bucket::Year),
+        Some((key, _)) => Err(ApiError::malformed_field(key, "expected week, month or year")),
+    }
+}
@@ -0,0 +1,39 @@
+use std::fmt::Display;
+
+use regex::Regex;
+
+use error::ApiError;
+
+lazy_static! {
+    static ref EMAIL_RE: Regex = Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s]+$").unwrap();
+}
+
+/// Field constraints of an entity, checked on create and after a partial update was merged
+pub trait Validate {
+    fn validate(&self) -> Result<(), ApiError>;
+}
+
+pub fn max_length(field: &str, value: &str, max: usize) -> Result<(), ApiError> {
+    if value.is_empty() {
+        return Err(ApiError::unprocessable(field, "must not be empty"));
+    }
+    if value.chars().count() > max {
+        return Err(ApiError::unprocessable(field, &format!("must be at most {} characters", max)));
+    }
+    Ok(())
+}
+
+pub fn range<T: PartialOrd + Display>(field: &str, value: T, min: T, max: T) -> Result<(), ApiError> {
+    if value < min || value > max {
+        return Err(ApiError::unprocessable(field, &format!("must be between {} and {}", min, max)));
+    }
+    Ok(())
+}
+
+pub fn email(field: &str, value: &str, max: usize) -> Result<(), ApiError> {
+    max_length(field, value, max)?;
+    if !EMAIL_RE.is_match(value) {
+        return Err(ApiError::unprocessable(field, "invalid email"));
+    }
+    Ok(())
+}